@@ -0,0 +1,395 @@
+// `cargo rustformat`: reads the Cargo.toml of the package (and of the members of the workspace),
+// collects the roots of all lib, bin, test, example and bench targets and runs rustformat --crate
+// on them. Everything that isn't about selecting packages is passed through to rustformat.
+extern crate toml;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
+use std::process::Command;
+
+// shared with rustformat, only some of the codes are used here
+#[allow(dead_code)]
+mod exit_code;
+mod common;
+
+use common::{
+    parse_toml,
+    print_error,
+    strings,
+};
+use exit_code::{
+    EXIT_IO_ERROR,
+    EXIT_USAGE,
+};
+use toml::{
+    Table,
+    Value,
+};
+
+static MANIFEST_NAME: &'static str = "Cargo.toml";
+
+// the directories targets are discovered in, with the key that turns the discovery off
+static TARGET_DIRS:[(&'static str, &'static str); 4] = [("src/bin", "autobins"), ("tests",
+    "autotests"), ("examples", "autoexamples"), ("benches", "autobenches")];
+
+// the roots cargo finds without a [lib] or [[bin]]
+static DEFAULT_ROOTS:[&'static str; 2] = ["src/lib.rs", "src/main.rs"];
+
+// the tables that declare targets explicitly, [lib] is a single table, the others are arrays
+static TARGET_TABLES:[&'static str; 5] = ["lib", "bin", "test", "example", "bench"];
+
+struct Args {
+    // -p, empty means all packages
+    packages:Vec < String > ,
+    manifest_path:Option < PathBuf > ,
+    // handed to rustformat as they are
+    passthrough:Vec < String > ,
+}
+
+struct Package {
+    name:String,
+    targets:Vec < PathBuf > ,
+}
+
+fn parse_args(args: & [String]) -> Result < Args,
+String > {
+    let mut parsed = Args {
+        packages:Vec::new(),
+        manifest_path:None,
+        passthrough:Vec::new(),
+    };
+    let mut args = args.iter();
+    loop {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => break,
+        };
+        if arg == "-p" || arg == "--package" {
+            match args.next() {
+                Some(name) => parsed.packages.push(name.clone()),
+                None => return Err(format!("option `{}` requires a value", arg)),
+            }
+        } else if arg == "--manifest-path" {
+            match args.next() {
+                Some(path) => parsed.manifest_path = Some(PathBuf::from(path)),
+                None => return Err(format!("option `{}` requires a value", arg)),
+            }
+        } else {
+            parsed.passthrough.push(arg.clone());
+        }
+    }
+    Ok(parsed)
+}
+
+// the nearest Cargo.toml, starting in the current directory
+fn find_manifest() -> Result < PathBuf,
+String > {
+    let mut dir = try!(env::current_dir().map_err( | e | format!("{}", e)));
+    loop {
+        let candidate = dir.join(MANIFEST_NAME);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+        if !dir.pop() {
+            return Err(format!("could not find {} here or in any parent directory",
+                MANIFEST_NAME));
+        }
+    }
+}
+
+fn read_manifest(manifest: &Path) -> Result < Table,
+String > {
+    let mut text = String::new();
+    let read = File::open(manifest).and_then( | mut f | f.read_to_string(&mut text));
+    if let Err(e) = read {
+        return Err(format!("{}: {}", manifest.display(), e));
+    }
+    parse_toml(&text).map_err( | e | format!("{}:{}: {}", manifest.display(), e.0, e.1))
+}
+
+// The package of the manifest and the members of its workspace.
+fn read_workspace(manifest: &Path) -> Result < Vec < Package > ,
+String > {
+    let table = try!(read_manifest(manifest));
+    let dir = manifest.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut packages = Vec::new();
+    if table.contains_key("package") {
+        packages.push(try!(read_package(&dir, &table, manifest)));
+    }
+
+    let workspace = try!(field(&table, "workspace", "table", manifest));
+    if let Some(&Value::Table(ref workspace)) = workspace {
+        let excluded = try!(array(workspace, "exclude", manifest));
+        for pattern in try!(array(workspace, "members", manifest)) {
+            for member_dir in try!(expand_member(&dir, &pattern)) {
+                if excluded.iter().any( | e | dir.join(e) == member_dir) || member_dir == dir {
+                    continue;
+                }
+                let member_manifest = member_dir.join(MANIFEST_NAME);
+                let member = try!(read_manifest(&member_manifest));
+                packages.push(try!(read_package(&member_dir, &member, &member_manifest)));
+            }
+        }
+    }
+
+    if packages.is_empty() {
+        return Err(format!("{}: neither [package] nor [workspace] found", manifest.display()));
+    }
+    Ok(packages)
+}
+
+// members are paths relative to the workspace root, a trailing /* stands for all the packages in
+// that directory
+fn expand_member(root: &Path, pattern: &str) -> Result < Vec < PathBuf > ,
+String > {
+    let prefix = pattern.trim_right_matches("/*");
+    if prefix.contains('*') || prefix.contains('?') || prefix.contains('[') {
+        return Err(format!("unsupported workspace member pattern `{}`", pattern));
+    }
+    if prefix.len() == pattern.len() {
+        return Ok(vec! [root.join(pattern)]);
+    }
+
+    let mut dirs = Vec::new();
+    let members_dir = root.join(prefix);
+    let entries = try!(fs::read_dir(&members_dir).map_err( | e | format!("{}: {}", prefix, e)));
+    for entry in entries {
+        let path = try!(entry.map_err( | e | format!("{}", e))).path();
+        if path.join(MANIFEST_NAME).is_file() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn read_package(dir: &Path, table: &Table, manifest: &Path) -> Result < Package,
+String > {
+    let package = match try!(field(table, "package", "table", manifest)) {
+        Some(&Value::Table(ref package)) => package.clone(),
+        _ => return Err(format!("{}: no [package] found", manifest.display())),
+    };
+    let name = match try!(field(&package, "name", "string", manifest)) {
+        Some(&Value::String(ref name)) => name.clone(),
+        _ => return Err(format!("{}: the package has no name", manifest.display())),
+    };
+
+    let mut targets = Vec::new();
+    // explicitly declared targets first, they may live anywhere
+    for key in TARGET_TABLES.iter() {
+        for target in try!(target_tables(table, key, manifest)) {
+            let path = try!(field(&target, "path", "string", manifest));
+            if let Some(&Value::String(ref path)) = path {
+                push_new(&mut targets, dir.join(path));
+            }
+        }
+    }
+
+    for name in DEFAULT_ROOTS.iter() {
+        let path = dir.join(name);
+        if path.is_file() {
+            push_new(&mut targets, path);
+        }
+    }
+    for pair in TARGET_DIRS.iter() {
+        let (target_dir, auto_key) = *pair;
+        let auto = try!(field(&package, auto_key, "boolean", manifest));
+        if let Some(&Value::Boolean(false)) = auto {
+            continue;
+        }
+        for path in try!(discover_targets(&dir.join(target_dir))) {
+            push_new(&mut targets, path);
+        }
+    }
+
+    Ok(Package {
+        name:name,
+        targets:targets,
+    })
+}
+
+// foo.rs and foo/main.rs in the directory, if it exists
+fn discover_targets(dir: &Path) -> Result < Vec < PathBuf > ,
+String > {
+    let mut targets = Vec::new();
+    if !dir.is_dir() {
+        return Ok(targets);
+    }
+    let entries = try!(fs::read_dir(dir).map_err( | e | format!("{}: {}", dir.display(), e)));
+    for entry in entries {
+        let path = try!(entry.map_err( | e | format!("{}", e))).path();
+        if path.is_dir() {
+            if path.join("main.rs").is_file() {
+                targets.push(path.join("main.rs"));
+            }
+        } else if path.extension().map_or(false, | ext | ext == "rs") {
+            targets.push(path);
+        }
+    }
+    targets.sort();
+    Ok(targets)
+}
+
+// [lib] is a table, [[bin]] and the others are arrays of tables
+fn target_tables(table: &Table, key: &str, manifest: &Path) -> Result < Vec < Table > ,
+String > {
+    let mut tables = Vec::new();
+    match table.get(key) {
+        Some(&Value::Table(ref target)) => tables.push(target.clone()),
+        Some(&Value::Array(ref values)) => {
+            for value in values {
+                if let Value::Table(ref target) = *value {
+                    tables.push(target.clone());
+                }
+            }
+        }
+        Some(value) => return Err(type_error(manifest, key, "table", value)),
+        None => {}
+    }
+    Ok(tables)
+}
+
+// The value of the key, if it is there and of the expected type.
+fn field < 'a > (table: &'a Table,
+    key: &str,
+    expected: &str,
+    manifest: &Path) -> Result < Option < &'a Value > ,
+String > {
+    match table.get(key) {
+        Some(value) => {
+            if value.type_str() == expected {
+                Ok(Some(value))
+            } else {
+                Err(type_error(manifest, key, expected, value))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// an array of strings, missing means empty
+fn array(table: &Table, key: &str, manifest: &Path) -> Result < Vec < String > ,
+String > {
+    match try!(field(table, key, "array", manifest)) {
+        Some(value) => strings(key, value).map_err( | e | format!("{}: {}", manifest.display(), e)),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn type_error(manifest: &Path, key: &str, expected: &str, found: &Value) -> String {
+    format!("{}: `{}` must be a {}, found a {}",
+        manifest.display(),
+        key,
+        expected,
+        found.type_str())
+}
+
+fn push_new(paths: &mut Vec < PathBuf > , path: PathBuf) {
+    if !paths.contains(&path) {
+        paths.push(path);
+    }
+}
+
+// the rustformat next to this binary, so that both come from the same install, otherwise the one
+// in the PATH
+fn rustformat_binary() -> PathBuf {
+    let name = format!("rustformat{}", env::consts::EXE_SUFFIX);
+    if let Ok(exe) = env::current_exe() {
+        let sibling = exe.with_file_name(&name);
+        if sibling.is_file() {
+            return sibling;
+        }
+    }
+    PathBuf::from(name)
+}
+
+// paths below the current directory are shown relative to it
+fn display_path(path: &Path, cwd: &Path) -> String {
+    match path.strip_prefix(cwd) {
+        Ok(relative) => relative.display().to_string(),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+fn print_usage() {
+    println!("Usage: cargo rustformat [options] [rustformat options]");
+    println!("");
+    println!("Formats all targets of the package, or of all packages of the workspace.");
+    println!("");
+    println!("Options:");
+    println!("    -p, --package <name>     format only this package, can be repeated");
+    println!("    --manifest-path <path>   the Cargo.toml to use instead of the nearest one");
+    println!("");
+    println!("Everything else, e.g. --check or --emit diff, is passed to rustformat.");
+}
+
+pub fn main() {
+    let args:Vec < String > = env::args().collect();
+    // cargo calls us as `cargo-rustformat rustformat [args]`
+    let skip = if args.len() > 1 && args[1] == "rustformat" {
+        2
+    } else {
+        1
+    };
+    if args.iter().skip(skip).any( | arg | arg == "-h" || arg == "--help") {
+        print_usage();
+        process::exit(0);
+    }
+    let args = match parse_args(&args [skip..]) {
+        Ok(args) => args,
+        Err(msg) => {
+            println!("{}", msg);
+            print_usage();
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let manifest = match args.manifest_path.clone() {
+        Some(path) => Ok(path),
+        None => find_manifest(),
+    };
+    let packages = match manifest.and_then( | manifest | read_workspace(&manifest)) {
+        Ok(packages) => packages,
+        Err(msg) => {
+            print_error(&msg);
+            process::exit(EXIT_IO_ERROR);
+        }
+    };
+
+    for name in &args.packages {
+        if !packages.iter().any( | package | &package.name == name) {
+            print_error(&format!("package `{}` not found in the workspace", name));
+            process::exit(EXIT_USAGE);
+        }
+    }
+    let cwd = env::current_dir().unwrap_or(PathBuf::new());
+    let mut roots = Vec::new();
+    for package in &packages {
+        if args.packages.is_empty() || args.packages.contains(&package.name) {
+            for target in &package.targets {
+                roots.push(display_path(target, &cwd));
+            }
+        }
+    }
+    if roots.is_empty() {
+        print_error("no targets found");
+        process::exit(0);
+    }
+
+    let binary = rustformat_binary();
+    let status = Command::new(&binary).arg("--crate").args(&args.passthrough).args(&roots).status();
+    match status {
+        Ok(status) => process::exit(status.code().unwrap_or(EXIT_IO_ERROR)),
+        Err(e) => {
+            print_error(&format!("could not run {}: {}", binary.display(), e));
+            process::exit(EXIT_IO_ERROR);
+        }
+    }
+}
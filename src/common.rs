@@ -0,0 +1,49 @@
+// Helpers shared by rustformat and cargo-rustformat, both read TOML files and report errors the
+// same way.
+use std::io;
+use std::io::prelude::*;
+
+use toml;
+use toml::{
+    Table,
+    Value,
+};
+
+// Errors and the summary go to stderr, so that they don't get mixed into diffs or stdin mode
+// output.
+pub fn print_error(msg: &str) {
+    let mut stderr = io::stderr();
+    let _ = writeln!(&mut stderr, "{}", msg);
+}
+
+// the line (counted from 1) and the message of the first error
+pub fn parse_toml(text: &str) -> Result < Table,
+(usize, String) > {
+    let mut parser = toml::Parser::new(text);
+    match parser.parse() {
+        Some(table) => Ok(table),
+        None => {
+            let e = &parser.errors[0];
+            let (line, _) = parser.to_linecol(e.lo);
+            Err((line + 1, e.desc.clone()))
+        }
+    }
+}
+
+pub fn strings(key: &str, value: &Value) -> Result < Vec < String > ,
+String > {
+    let expected = "an array of strings";
+    let values = match *value {
+        Value::Array(ref values) => values,
+        _ => return Err(format!("`{}` must be {}, found a {}", key, expected, value.type_str())),
+    };
+    let mut strings = Vec::new();
+    for v in values {
+        match *v {
+            Value::String(ref s) => strings.push(s.clone()),
+            _ => return Err(format!("`{}` must be {}, found a {} in it", key, expected,
+                v.type_str())),
+        }
+    }
+    Ok(strings)
+}
@@ -0,0 +1,476 @@
+// The settings of the typesetter. They are read from a rustformat.toml in the directory of the
+// formated file or one of its parents, the nearest one wins.
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Mutex;
+
+use common::{
+    parse_toml,
+    strings,
+};
+use toml::Value;
+
+pub static CONFIG_FILE_NAME: &'static str = "rustformat.toml";
+
+// in the order they are printed by --print-config
+static KEYS:[&'static str; 5] = ["tab_spaces", "max_width", "max_indent", "newline_style",
+    "expression_macros"];
+
+// macros that take expressions, they are set like calls or array literals whatever their delimiter
+static EXPRESSION_MACROS:[&'static str; 19] = ["assert", "assert_eq", "assert_ne", "debug_assert",
+    "debug_assert_eq", "debug_assert_ne", "eprint", "eprintln", "format", "format_args", "matches",
+    "panic", "print", "println", "try", "unreachable", "vec", "write", "writeln"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // indentation per level
+    pub tab_spaces:i32,
+    // lines longer than this get broken
+    pub max_width:i32,
+    // indentation doesn't grow beyond this, defaults to 4/5 of max_width
+    pub max_indent:i32,
+    // the line endings of the formated source
+    pub newline_style:NewlineStyle,
+    // the names of the macros that take expressions, without the !
+    pub expression_macros:Vec < String > ,
+    // where each setting came from, settings that are missing have their default value
+    origins:BTreeMap < String,
+    Origin > ,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewlineStyle {
+    Unix,
+    Windows,
+    // whatever the platform rustformat runs on uses
+    Native,
+    // the same as the first line ending of the source
+    Auto,
+}
+
+impl NewlineStyle {
+    fn from_name(name: &str) -> Option < NewlineStyle > {
+        match name {
+            "unix" => Some(NewlineStyle::Unix),
+            "windows" => Some(NewlineStyle::Windows),
+            "native" => Some(NewlineStyle::Native),
+            "auto" => Some(NewlineStyle::Auto),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            NewlineStyle::Unix => "unix",
+            NewlineStyle::Windows => "windows",
+            NewlineStyle::Native => "native",
+            NewlineStyle::Auto => "auto",
+        }
+    }
+
+    // the line ending to use for the given source
+    pub fn line_ending(&self, source: &str) -> &'static str {
+        match *self {
+            NewlineStyle::Unix => "\n",
+            NewlineStyle::Windows => "\r\n",
+            NewlineStyle::Native => {
+                if cfg!(windows) {
+                    "\r\n"
+                } else {
+                    "\n"
+                }
+            }
+            NewlineStyle::Auto => {
+                match source.find('\n') {
+                    Some(i) => {
+                        if source[..i].ends_with("\r") {
+                            "\r\n"
+                        } else {
+                            "\n"
+                        }
+                    }
+                    None => NewlineStyle::Native.line_ending(source),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Default,
+    // the file and the line the key is set on
+    File(PathBuf, usize),
+    CommandLine,
+    // computed from another setting
+    Derived(&'static str),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Origin::Default => write!(f, "default"),
+            Origin::File(ref file, line) => write!(f, "{}:{}", file.display(), line),
+            Origin::CommandLine => write!(f, "--config"),
+            Origin::Derived(key) => write!(f, "derived from {}", key),
+        }
+    }
+}
+
+// A key=value pair given with --config.
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub key:String,
+    pub value:Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub file:PathBuf,
+    pub line:usize,
+    pub message:String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tab_spaces:4,
+            max_width:100,
+            max_indent:80,
+            newline_style:NewlineStyle::Auto,
+            expression_macros:EXPRESSION_MACROS.iter().map( | name | name.to_string()).collect(),
+            origins:BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    // The config that applies to the given file: the nearest rustformat.toml (or the defaults)
+    // with the overrides from the command line on top.
+    pub fn for_file(path: &Path, overrides: & [Override]) -> Result < Config,
+    ConfigError > {
+        let mut config = match find_config_file(path) {
+            Some(config_file) => try!(Config::from_file(&config_file)),
+            None => Config::default(),
+        };
+        for o in overrides {
+            if let Err(message) = config.set(&o.key, &o.value, Origin::CommandLine) {
+                return Err(error(&Origin::CommandLine, message));
+            }
+        }
+        try!(config.finish());
+        Ok(config)
+    }
+
+    fn from_file(config_file: &Path) -> Result < Config,
+    ConfigError > {
+        let mut text = String::new();
+        let read = File::open(config_file).and_then( | mut f | f.read_to_string(&mut text));
+        if let Err(e) = read {
+            return Err(error(&Origin::File(config_file.to_path_buf(), 0), format!("{}", e)));
+        }
+        let table = match parse_toml(&text) {
+            Ok(table) => table,
+            Err((line, message)) => {
+                return Err(ConfigError {
+                    file:config_file.to_path_buf(),
+                    line:line,
+                    message:message,
+                });
+            }
+        };
+        let mut config = Config::default();
+        for key in table.keys() {
+            let origin = Origin::File(config_file.to_path_buf(), key_line(&text, key));
+            if let Err(message) = config.set(key, &table [key], origin.clone()) {
+                return Err(error(&origin, message));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &Value, origin: Origin) -> Result < (),
+    String > {
+        match key {
+            "tab_spaces" => self.tab_spaces = try!(integer_in(key, value, 1, 16)),
+            "max_width" => self.max_width = try!(integer_in(key, value, 10, 1000)),
+            "max_indent" => self.max_indent = try!(integer_in(key, value, 0, 1000)),
+            "newline_style" => self.newline_style = try!(newline_style(key, value)),
+            "expression_macros" => self.expression_macros = try!(strings(key, value)),
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        self.origins.insert(key.to_string(), origin);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Value {
+        match key {
+            "tab_spaces" => Value::Integer(self.tab_spaces as i64),
+            "max_width" => Value::Integer(self.max_width as i64),
+            "max_indent" => Value::Integer(self.max_indent as i64),
+            "newline_style" => Value::String(self.newline_style.name().to_string()),
+            "expression_macros" => {
+                let names = self.expression_macros.iter().map( | name |
+                    Value::String(name.clone()));
+                Value::Array(names.collect())
+            }
+            _ => panic!("unknown key `{}`", key),
+        }
+    }
+
+    pub fn origin(&self, key: &str) -> Origin {
+        self.origins.get(key).cloned().unwrap_or(Origin::Default)
+    }
+
+    // Fills in the settings that depend on others and checks that they fit together.
+    fn finish(&mut self) -> Result < (),
+    ConfigError > {
+        let width_set = self.origin("max_width") != Origin::Default;
+        if width_set && self.origin("max_indent") == Origin::Default {
+            // keep the default proportion to max_width (80 of 100)
+            self.max_indent = self.max_width * 4 / 5;
+            self.origins.insert("max_indent".to_string(), Origin::Derived("max_width"));
+        }
+        if self.max_indent >= self.max_width {
+            let message = format!("max_indent ({}) must be smaller than max_width ({})",
+                self.max_indent,
+                self.max_width);
+            return Err(error(&self.origin("max_indent"), message));
+        }
+        Ok(())
+    }
+
+    // The settings as TOML, with a comment saying where each one came from.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        for key in KEYS.iter() {
+            let line = format!("{} = {}", key, format_value(&self.get(key)));
+            out.push_str(&format!("{:<30}# {}\n", line, self.origin(key)));
+        }
+        out
+    }
+}
+
+// The configs of the directories seen so far, so that the rustformat.toml that applies to a
+// directory is read once and not again for every file in it.
+pub struct ConfigCache {
+    overrides:Vec < Override > ,
+    configs:Mutex < HashMap < PathBuf,
+    Result < Config,
+    ConfigError >> > ,
+}
+
+impl ConfigCache {
+    pub fn new(overrides: & [Override]) -> ConfigCache {
+        ConfigCache {
+            overrides:overrides.to_vec(),
+            configs:Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_file(&self, path: &Path) -> Result < Config,
+    ConfigError > {
+        let dir = directory_of(path);
+        if let Some(result) = self.configs.lock().unwrap().get(&dir) {
+            return result.clone();
+        }
+        // not locked while reading, two jobs may read the same file but get the same result
+        let result = Config::for_file(&dir, &self.overrides);
+        self.configs.lock().unwrap().insert(dir, result.clone());
+        result
+    }
+}
+
+// The line (counted from 1) the key is set on, as key = ... or as a [key] table, 0 if it isn't
+// found.
+fn key_line(text: &str, key: &str) -> usize {
+    let mut number = 0usize;
+    for line in text.lines() {
+        number += 1;
+        let line = line.trim_left().trim_left_matches('"');
+        if !line.starts_with(key) {
+            if line.trim_right() == format!("[{}]", key) {
+                return number;
+            }
+            continue;
+        }
+        if line[key.len()..].trim_left_matches('"').trim_left().starts_with("=") {
+            return number;
+        }
+    }
+    0
+}
+
+// parses the argument of --config, values that aren't valid TOML are taken as strings
+pub fn parse_override(arg: &str) -> Result < Override,
+String > {
+    let pos = match arg.find('=') {
+        Some(pos) => pos,
+        None => return Err(format!("`{}` is not of the form key=value", arg)),
+    };
+    let key = arg[..pos].trim().to_string();
+    let text = arg[pos + 1..].trim();
+    let value = match parse_toml(&format!("value = {}", text)) {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => Value::String(text.to_string()),
+    };
+    // catch unknown keys and bad values right away
+    try!(Config::default().set(&key, &value, Origin::CommandLine));
+    Ok(Override {
+        key:key,
+        value:value,
+    })
+}
+
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => format!("{:?}", s),
+        Value::Integer(i) => format!("{}", i),
+        Value::Float(f) => format!("{}", f),
+        Value::Boolean(b) => format!("{}", b),
+        Value::Datetime(ref d) => d.clone(),
+        Value::Array(ref values) => {
+            let values:Vec < String > = values.iter().map(format_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+        Value::Table(_) => "{ ... }".to_string(),
+    }
+}
+
+fn integer_in(key: &str, value: &Value, min: i64, max: i64) -> Result < i32,
+String > {
+    match *value {
+        Value::Integer(i) => {
+            if i >= min && i <= max {
+                Ok(i as i32)
+            } else {
+                Err(format!("`{}` must be between {} and {}, found {}", key, min, max, i))
+            }
+        }
+        _ => Err(format!("`{}` must be an integer, found a {}", key, value.type_str())),
+    }
+}
+
+fn newline_style(key: &str, value: &Value) -> Result < NewlineStyle,
+String > {
+    let expected = "unix, windows, native or auto";
+    match *value {
+        Value::String(ref name) => {
+            match NewlineStyle::from_name(name) {
+                Some(style) => Ok(style),
+                None => Err(format!("`{}` must be one of {}, found `{}`", key, expected, name)),
+            }
+        }
+        _ => Err(format!("`{}` must be one of {}, found a {}", key, expected, value.type_str())),
+    }
+}
+
+fn error(origin: &Origin, message: String) -> ConfigError {
+    let (file, line) = match *origin {
+        Origin::File(ref file, line) => (file.clone(), line),
+        _ => (PathBuf::from(format!("{}", origin)), 0),
+    };
+    ConfigError {
+        file:file,
+        line:line,
+        message:message,
+    }
+}
+
+// The directory of the file, or the path itself if it is a directory.
+fn directory_of(path: &Path) -> PathBuf {
+    // the file itself doesn't have to exist, e.g. for --stdin-filepath
+    let mut dir = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => {
+            match env::current_dir() {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => path.to_path_buf(),
+            }
+        }
+    };
+    if !dir.is_dir() {
+        dir.pop();
+    }
+    dir
+}
+
+// Looks for rustformat.toml in the directory of the file (or in the directory itself) and all its
+// parents.
+fn find_config_file(path: &Path) -> Option < PathBuf > {
+    let mut dir = directory_of(path);
+    loop {
+        let candidate = dir.join(CONFIG_FILE_NAME);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+
+#[test]
+fn test_overrides() {
+    let overrides = vec![parse_override("max_width=60").unwrap()];
+    let config = Config::for_file(Path::new("/"), &overrides).unwrap();
+    assert_eq!(config.max_width, 60);
+    assert_eq!(config.max_indent, 48);
+    assert_eq!(config.origin("max_indent"), Origin::Derived("max_width"));
+    assert!(parse_override("tab_spaces").is_err());
+    assert!(parse_override("tab_spaces=\"4\"").is_err());
+    assert!(parse_override("no_such_key=1").is_err());
+    let overrides = vec![parse_override("expression_macros=[\"vec\", \"html\"]").unwrap()];
+    let config = Config::for_file(Path::new("/"), &overrides).unwrap();
+    assert_eq!(config.expression_macros, vec! ["vec", "html"]);
+    assert!(parse_override("expression_macros=[1]").is_err());
+}
+
+#[test]
+fn test_parse_toml() {
+    let table = parse_toml("max_width = 120\nexpression_macros = [\"vec\"]").unwrap();
+    assert_eq!(table ["max_width"], Value::Integer(120));
+    assert_eq!(parse_toml("a = 1\na = 2").unwrap_err().0, 2);
+}
+
+#[test]
+fn test_error_lines() {
+    let dir = env::temp_dir().join("rustformat-test-config");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let config_file = dir.join(CONFIG_FILE_NAME);
+    let line_of = | text: &str | {
+        File::create(&config_file).unwrap().write_all(text.as_bytes()).unwrap();
+        Config::for_file(&dir, & []).unwrap_err().line
+    };
+    assert_eq!(line_of("tab_spaces = 2\nno_such_key = 1"), 2);
+    assert_eq!(line_of("\n\nnewline_style = 1"), 3);
+    assert_eq!(line_of("[tab_spaces]\nx = 1"), 1);
+    assert_eq!(line_of("max_indent = 90\n  max_width = 5000"), 2);
+    assert_eq!(line_of("\n\"tab_spaces\" = 0"), 2);
+    // a setting that clashes with another one
+    assert_eq!(line_of("tab_spaces = 2\nmax_width = 10\nmax_indent = 20"), 3);
+    assert_eq!(line_of("max_width = 50\nmax_indent = 60"), 2);
+    let _ = fs::remove_dir_all(&dir);
+}
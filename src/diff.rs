@@ -0,0 +1,304 @@
+// A small unified diff implementation, so that the changes rustformat would make can be reviewed
+// (and applied with patch or git apply) before any file is touched.
+
+static CONTEXT_LINES:usize = 3;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Edit {
+    Equal(usize, usize),  // index into old, index into new
+    Delete(usize),
+    Insert(usize),
+}
+
+// Returns a unified diff between old and new, or an empty string if they are equal. The names
+// are used for the file headers.
+pub fn unified_diff(name: &str, old: &str, new: &str, color: bool) -> String {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let edits = shortest_edit(&old_lines, &new_lines);
+
+    let mut out = String::new();
+    let mut index = 0usize;
+    loop {
+        // find the next change
+        while index < edits.len() && is_equal(&edits [index]) {
+            index += 1;
+        }
+        if index >= edits.len() {
+            break;
+        }
+        let start = if index > CONTEXT_LINES {
+            index - CONTEXT_LINES
+        } else {
+            0
+        };
+
+        // extend the hunk as long as the changes are close enough to each other
+        let mut end = index;
+        let mut equal_run = 0usize;
+        while end < edits.len() {
+            if is_equal(&edits [end]) {
+                equal_run += 1;
+                if equal_run > 2 * CONTEXT_LINES {
+                    // the line is part of the run that is trimmed below
+                    end += 1;
+                    break;
+                }
+            } else {
+                equal_run = 0;
+            }
+            end += 1;
+        }
+        if equal_run > CONTEXT_LINES {
+            end -= equal_run - CONTEXT_LINES;
+        }
+
+        if out.is_empty() {
+            push_colored(&mut out, &format!("--- {}", prefixed("a", name)), "1", color);
+            push_colored(&mut out, &format!("+++ {}", prefixed("b", name)), "1", color);
+        }
+        push_hunk(&mut out, &edits [start..end], &old_lines, &new_lines, color);
+        index = end;
+    }
+    out
+}
+
+// a/foo.rs for a relative name, a/abs/foo.rs for an absolute one
+fn prefixed(side: &str, name: &str) -> String {
+    format!("{}/{}", side, name.trim_left_matches('/'))
+}
+
+fn push_hunk(out: &mut String, edits: & [Edit], old: & [&str], new: & [&str], color: bool) {
+    let mut old_start = old.len();
+    let mut new_start = new.len();
+    let mut old_count = 0usize;
+    let mut new_count = 0usize;
+
+    // the position of the first line on both sides, even if the hunk starts with an insertion or
+    // a deletion
+    for edit in edits {
+        match *edit {
+            Edit::Equal(o, n) => {
+                old_start = ::std::cmp::min(old_start, o);
+                new_start = ::std::cmp::min(new_start, n);
+            }
+            Edit::Delete(o) => old_start = ::std::cmp::min(old_start, o),
+            Edit::Insert(n) => new_start = ::std::cmp::min(new_start, n),
+        }
+    }
+    for edit in edits {
+        match *edit {
+            Edit::Equal(_, _) => {
+                old_count += 1;
+                new_count += 1;
+            }
+            Edit::Delete(_) => old_count += 1,
+            Edit::Insert(_) => new_count += 1,
+        }
+    }
+    if old_count == 0 {
+        old_start = preceding_line(edits, true);
+    }
+    if new_count == 0 {
+        new_start = preceding_line(edits, false);
+    }
+
+    let header = format!("@@ -{} +{} @@", range(old_start, old_count), range(new_start, new_count));
+    push_colored(out, &header, "36", color);
+    for edit in edits {
+        match *edit {
+            Edit::Equal(o, _) => push_line(out, " ", old [o], "0", false),
+            Edit::Delete(o) => push_line(out, "-", old [o], "31", color),
+            Edit::Insert(n) => push_line(out, "+", new [n], "32", color),
+        }
+    }
+}
+
+// For an empty side of a hunk, diff expects the number of the line after which the change
+// happens.
+fn preceding_line(edits: & [Edit], old_side: bool) -> usize {
+    for edit in edits {
+        match *edit {
+            Edit::Delete(o) => {
+                if !old_side {
+                    return o;
+                }
+            }
+            Edit::Insert(n) => {
+                if old_side {
+                    return n;
+                }
+            }
+            _ => {}
+        }
+    }
+    0
+}
+
+fn range(start: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", start)
+    } else if count == 1 {
+        format!("{}", start + 1)
+    } else {
+        format!("{},{}", start + 1, count)
+    }
+}
+
+fn push_line(out: &mut String, prefix: &str, line: &str, code: &str, color: bool) {
+    let text = format!("{}{}", prefix, line.trim_right_matches("\n"));
+    push_colored(out, &text, code, color);
+    if !line.ends_with("\n") {
+        out.push_str("\\ No newline at end of file\n");
+    }
+}
+
+fn push_colored(out: &mut String, text: &str, code: &str, color: bool) {
+    if color {
+        out.push_str(&format!("\x1b[{}m{}\x1b[0m\n", code, text));
+    } else {
+        out.push_str(text);
+        out.push_str("\n");
+    }
+}
+
+fn is_equal(edit: &Edit) -> bool {
+    match *edit {
+        Edit::Equal(_, _) => true,
+        _ => false,
+    }
+}
+
+// Splits the text into lines, every line keeps its line terminator, so that a missing newline at
+// the end of the file shows up as a difference.
+fn split_lines(text: &str) -> Vec < &str > {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    loop {
+        match rest.find('\n') {
+            Some(i) => {
+                lines.push(&rest [..i + 1]);
+                rest = &rest[i + 1..];
+            }
+            None => break,
+        }
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    lines
+}
+
+// Myers' O(ND) algorithm. For every round d the furthest reaching paths are kept, so that the
+// edit script can be reconstructed by walking back from the end.
+fn shortest_edit(old: & [&str], new: & [&str]) -> Vec < Edit > {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let offset = n + m + 1;
+    let mut v = vec![0isize; (2 * offset + 1) as usize];
+    let mut trace = Vec::new();
+
+    let mut done = n == 0 && m == 0;
+    let mut d = 0isize;
+    loop {
+        if done {
+            break;
+        }
+        trace.push(v.clone());
+        let mut k = - d;
+        while k <= d && !done {
+            let mut x = if moves_down(&v, offset, k, d) {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            done = x >= n && y >= m;
+            k += 2;
+        }
+        d += 1;
+    }
+
+    let mut edits = Vec::new();
+    let mut x = n;
+    let mut y = m;
+    let mut d = trace.len() as isize;
+    while d > 0 {
+        d -= 1;
+        let v = &trace[d as usize];
+        let k = x - y;
+        let prev_k = if moves_down(v, offset, k, d) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(offset + prev_k) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert((y - 1) as usize));
+            } else {
+                edits.push(Edit::Delete((x - 1) as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+// whether the path to diagonal k comes from diagonal k + 1 (an insertion) or from k - 1 (a
+// deletion)
+fn moves_down(v: & [isize], offset: isize, k: isize, d: isize) -> bool {
+    k == - d || (k != d && v [(offset + k - 1) as usize] < v [(offset + k + 1) as usize])
+}
+
+#[test]
+fn test_unified_diff() {
+    let old = "a\nb\nc\n";
+    let new = "a\nB\nc";
+    let expected = ["--- a/foo.rs",
+        "+++ b/foo.rs",
+        "@@ -1,3 +1,3 @@",
+        " a",
+        "-b",
+        "-c",
+        "+B",
+        "+c",
+        "\\ No newline at end of file",
+        ""].join("\n");
+    assert_eq!(unified_diff("foo.rs", old, new, false), expected);
+    assert_eq!(unified_diff("foo.rs", old, old, false), "");
+}
+
+#[test]
+fn test_unified_diff_context() {
+    let old:Vec < String > = (1..21).map( | i | format!("l{}\n", i)).collect();
+    let mut new = old.clone();
+    new[4] = "changed\n".to_string();
+    let diff = unified_diff("/abs/foo.rs", &old.concat(), &new.concat(), false);
+    let expected = ["--- a/abs/foo.rs",
+        "+++ b/abs/foo.rs",
+        "@@ -2,7 +2,7 @@",
+        " l2",
+        " l3",
+        " l4",
+        "-l5",
+        "+changed",
+        " l6",
+        " l7",
+        " l8",
+        ""].join("\n");
+    assert_eq!(diff, expected);
+}
@@ -0,0 +1,76 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+
+use config::ConfigError;
+use token_handling::LexError;
+use verify::Divergence;
+
+// Everything that can go wrong while formatting a single file.
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    NotUtf8,
+    // a broken rustformat.toml
+    Config(ConfigError),
+    // the lexer found an error in the file, e.g. an unterminated string
+    Lex(String, LexError),
+    // the formated source doesn't have the same tokens as the original, nothing gets written
+    Verify(Divergence),
+    // formating the formated source again changed it, with the diff between the two passes
+    NotIdempotent(String),
+    // a bug in rustformat, the message is the one passed to panic!
+    Panic(String),
+}
+
+impl FormatError {
+    // io errors are reported differently from errors in the formatting itself, a broken config
+    // counts as the latter
+    pub fn is_io(&self) -> bool {
+        match *self {
+            FormatError::Io(_) | FormatError::NotUtf8 => true,
+            _ => false,
+        }
+    }
+
+    // turns the payload of a panicked formatting thread into an error
+    pub fn from_panic(payload: Box < Any + Send > ) -> FormatError {
+        if let Some(s) = payload.downcast_ref::< &'static str > () {
+            FormatError::Panic(s.to_string())
+        } else if let Some(s) = payload.downcast_ref::< String > () {
+            FormatError::Panic(s.clone())
+        } else {
+            FormatError::Panic("unknown panic".to_string())
+        }
+    }
+}
+
+impl From < io::Error > for FormatError {
+    fn from(e: io::Error) -> FormatError {
+        FormatError::Io(e)
+    }
+}
+
+impl From < ConfigError > for FormatError {
+    fn from(e: ConfigError) -> FormatError {
+        FormatError::Config(e)
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::Io(ref e) => write!(f, "{}", e),
+            FormatError::NotUtf8 => write!(f, "file is not valid UTF-8"),
+            FormatError::Config(ref e) => write!(f, "invalid config: {}", e),
+            FormatError::Lex(ref file, ref e) => write!(f, "{}:{}", file, e),
+            FormatError::Verify(ref d) => {
+                write!(f, "formating would change the code, the file is left untouched: {}", d)
+            }
+            FormatError::NotIdempotent(ref diff) => {
+                write!(f, "a second pass changes the formated source:\n{}", diff.trim_right())
+            }
+            FormatError::Panic(ref msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
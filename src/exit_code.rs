@@ -0,0 +1,6 @@
+// The exit codes of rustformat, cargo-rustformat passes them on. If there are several problems the
+// most severe one wins.
+pub static EXIT_CHANGES:i32 = 1;
+pub static EXIT_USAGE:i32 = 2;
+pub static EXIT_FORMAT_ERROR:i32 = 3;
+pub static EXIT_IO_ERROR:i32 = 4;
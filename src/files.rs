@@ -0,0 +1,398 @@
+// Turns the paths given on the command line into the list of files to format, either by walking
+// directories or by following the module tree of a crate.
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Error;
+use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use token_handling::{
+    lex,
+    Word,
+};
+
+// Directories are searched recursively for .rs files, everything else is taken as it is. A path
+// that can't be searched ends up in errors, the others are searched anyway.
+pub fn expand_paths(paths: & [String], errors: &mut Vec < (PathBuf, Error) > ) -> Vec < PathBuf > {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            walk_dir(path, &mut files, errors);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    files
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec < PathBuf > , errors: &mut Vec < (PathBuf, Error) > ) {
+    let mut entries = Vec::new();
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            errors.push((dir.to_path_buf(), e));
+            return;
+        }
+    };
+    for entry in read_dir {
+        match entry {
+            Ok(entry) => entries.push(entry.path()),
+            Err(e) => errors.push((dir.to_path_buf(), e)),
+        }
+    }
+    // read_dir has no defined order, but the output should be the same on every run
+    entries.sort();
+
+    for path in entries {
+        // symlinked directories are skipped, they can point back up the tree
+        let is_symlink = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type().is_symlink(),
+            Err(e) => {
+                errors.push((path, e));
+                continue;
+            }
+        };
+        if path.is_dir() {
+            if !is_symlink && !is_skipped_dir(&path) {
+                walk_dir(&path, files, errors);
+            }
+        } else if path.extension().map_or(false, | ext | ext == "rs") {
+            files.push(path);
+        }
+    }
+}
+
+// hidden directories (.git etc.) and cargos build directory
+fn is_skipped_dir(path: &Path) -> bool {
+    match path.file_name().and_then( | name | name.to_str()) {
+        Some(name) => name.starts_with(".") || name == "target",
+        None => false,
+    }
+}
+
+// Starts at the crate roots and follows the `mod foo;` declarations, so that only files that are
+// part of the crate are returned. Directories are searched for a lib.rs or main.rs, one without
+// them ends up in errors.
+pub fn crate_files(paths: & [String], errors: &mut Vec < (PathBuf, Error) > ) -> Vec < PathBuf > {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for path in paths {
+        let path = Path::new(path);
+        let roots = if path.is_dir() {
+            find_crate_roots(path)
+        } else {
+            vec![path.to_path_buf()]
+        };
+        if roots.is_empty() {
+            let msg = "no lib.rs or main.rs found";
+            errors.push((path.to_path_buf(), Error::new(io::ErrorKind::NotFound, msg)));
+        }
+        for root in roots {
+            follow_mods(&root, true, &mut files, &mut seen);
+        }
+    }
+    files
+}
+
+static CRATE_ROOTS:[&'static str; 4] = ["src/lib.rs", "src/main.rs", "lib.rs", "main.rs"];
+
+fn find_crate_roots(dir: &Path) -> Vec < PathBuf > {
+    let mut roots = Vec::new();
+    for name in CRATE_ROOTS.iter() {
+        let candidate = dir.join(name);
+        if candidate.is_file() {
+            roots.push(candidate);
+        }
+    }
+    roots
+}
+
+fn follow_mods(file: &Path, owns_dir: bool, files: &mut Vec < PathBuf > , seen: &mut HashSet <
+    PathBuf > ) {
+    if !seen.insert(file.to_path_buf()) {
+        return;
+    }
+    files.push(file.to_path_buf());
+
+    // a file that can't be read (missing, not UTF-8) is reported when it gets formated, the files
+    // next to it are still followed
+    let mut source = String::new();
+    if File::open(file).and_then( | mut f | f.read_to_string(&mut source)).is_err() {
+        return;
+    }
+
+    let file_dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+    // crate roots, mod.rs files and files included with #[path] keep their submodules next to
+    // them, foo.rs keeps them in foo/
+    let mod_dir = if owns_dir || file.file_name().map_or(false, | name | name == "mod.rs") {
+        file_dir.clone()
+    } else {
+        file_dir.join(file.file_stem().unwrap_or(file.as_os_str()))
+    };
+
+    for decl in find_mod_decls(&source) {
+        let (path, owns_dir) = match decl.path_attr {
+            Some(ref attr) => {
+                // #[path] is relative to the file, or to the directory of the enclosing inline
+                // module
+                let base = if decl.inline_mods.is_empty() {
+                    file_dir.clone()
+                } else {
+                    join_all(&mod_dir, &decl.inline_mods)
+                };
+                (base.join(attr), true)
+            }
+            None => {
+                let base = join_all(&mod_dir, &decl.inline_mods);
+                let file_rs = base.join(format!("{}.rs", decl.name));
+                let mod_rs = base.join(&decl.name).join("mod.rs");
+                if file_rs.is_file() {
+                    (file_rs, false)
+                } else if mod_rs.is_file() {
+                    (mod_rs, true)
+                } else {
+                    let mut stderr = io::stderr();
+                    let _ = writeln!(&mut stderr,
+                        "warning: no file found for module `{}` declared in {}",
+                        decl.name,
+                        file.display());
+                    continue;
+                }
+            }
+        };
+        follow_mods(&path, owns_dir, files, seen);
+    }
+}
+
+fn join_all(base: &Path, names: & [String]) -> PathBuf {
+    let mut path = base.to_path_buf();
+    for name in names {
+        path.push(name);
+    }
+    path
+}
+
+// A `mod foo;` declaration, together with the inline modules it is nested in and the value of a
+// #[path] attribute.
+struct ModDecl {
+    name:String,
+    inline_mods:Vec < String > ,
+    path_attr:Option < String > ,
+}
+
+fn find_mod_decls(source: &str) -> Vec < ModDecl > {
+    // a file the lexer chokes on has no submodules we can find, formating it reports the error
+    let words = lex(source).unwrap_or(Vec::new());
+    let words:Vec < Word > = words.into_iter().filter( | word | !is_layout(word)).collect();
+    let mut decls = Vec::new();
+    // the inline modules we are in, with the brace depth they were opened at
+    let mut inline_mods:Vec < (String, usize) > = Vec::new();
+    let mut depth = 0usize;
+    let mut path_attr = None;
+    let mut index = 0usize;
+
+    loop {
+        if index >= words.len() {
+            break;
+        }
+        let word = words[index].clone();
+        if word == Word::PrefixOperator("#".to_string()) {
+            let end = attribute_end(&words, index);
+            if let Some(path) = path_attribute(&words [index..end]) {
+                path_attr = Some(path);
+            }
+            index = end;
+            continue;
+        }
+
+        // pub(crate) and pub(in path) belong to the declaration like pub does
+        let restricted = words.get(index + 1) == Some(&Word::OpenParen);
+        if word == Word::Other("pub".to_string()) && restricted {
+            while index < words.len() && words[index] != Word::CloseParen {
+                index += 1;
+            }
+            index += 1;
+            continue;
+        }
+
+        if word == Word::Other("mod".to_string()) && index + 2 < words.len() {
+            if let Word::Other(ref name) = words[index + 1] {
+                if words[index + 2] == Word::SemiColon {
+                    decls.push(ModDecl {
+                        name:name.clone(),
+                        inline_mods:inline_mods.iter().map( | m | m.0.clone()).collect(),
+                        path_attr:path_attr.clone(),
+                    });
+                } else if words[index + 2] == Word::OpenBrace {
+                    // an inline module with a #[path] gets that directory instead
+                    let dir = path_attr.clone().unwrap_or(name.clone());
+                    inline_mods.push((dir, depth));
+                }
+            }
+        }
+
+        match word {
+            Word::OpenBrace => depth += 1,
+            Word::CloseBrace => {
+                if depth > 0 {
+                    depth -= 1;
+                }
+                if inline_mods.last().map_or(false, | m | m.1 == depth) {
+                    inline_mods.pop();
+                }
+            }
+            _ => {}
+        }
+        if word != Word::Other("pub".to_string()) && word != Word::Other("mod".to_string()) {
+            path_attr = None;
+        }
+        index += 1;
+    }
+    decls
+}
+
+// returns the index after the closing bracket of the attribute starting at index
+fn attribute_end(words: & [Word], index: usize) -> usize {
+    let mut depth = 0usize;
+    let mut end = index + 1;
+    loop {
+        if end >= words.len() {
+            return end;
+        }
+        match words[end] {
+            Word::OpenBracket => depth += 1,
+            Word::CloseBracket => {
+                if depth <= 1 {
+                    return end + 1;
+                }
+                depth -= 1;
+            }
+            Word::SlimInfix(_) | Word::PrefixOperator(_) => {}
+            _ => {
+                if depth == 0 {
+                    return end;
+                }
+            }
+        }
+        end += 1;
+    }
+}
+
+// #[path = "foo.rs"]
+fn path_attribute(attr: & [Word]) -> Option < String > {
+    if attr.len() == 6
+    && attr[2] == Word::Other("path".to_string())
+    && attr[3] == Word::BinaryOperator("=".to_string()) {
+        if let Word::Other(ref lit) = attr[4] {
+            return Some(unquote(lit));
+        }
+    }
+    None
+}
+
+// strips the quotes of a (raw) string literal and resolves the escapes of a normal one
+fn unquote(lit: &str) -> String {
+    if lit.starts_with("r") {
+        let lit = lit.trim_left_matches("r").trim_matches('#');
+        return strip_quotes(lit).to_string();
+    }
+    let lit = strip_quotes(lit);
+    let mut result = String::new();
+    let mut chars = lit.chars().peekable();
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break,
+        };
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('x') => {
+                let hex:String = chars.by_ref().take(2).collect();
+                push_code(&mut result, &hex);
+            }
+            Some('u') => {
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') | None => break,
+                        Some('{') => {}
+                        Some(c) => hex.push(c),
+                    }
+                }
+                push_code(&mut result, &hex);
+            }
+            // a line continuation skips the line break and the indentation after it
+            Some('\n') => {
+                loop {
+                    match chars.peek() {
+                        Some(&c) => {
+                            if !c.is_whitespace() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    }
+                    chars.next();
+                }
+            }
+            Some(c) => result.push(c),
+            None => {}
+        }
+    }
+    result
+}
+
+fn strip_quotes(lit: &str) -> &str {
+    if lit.len() >= 2 && lit.starts_with("\"") && lit.ends_with("\"") {
+        &lit[1..lit.len() - 1]
+    } else {
+        lit
+    }
+}
+
+fn push_code(result: &mut String, hex: &str) {
+    if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(::std::char::from_u32) {
+        result.push(c);
+    }
+}
+
+fn is_layout(word: &Word) -> bool {
+    match *word {
+        Word::LineBreak | Word::LineBreakDouble | Word::Comment(_) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_mod_decls() {
+    let source =
+    "#[path = \"a\\\\b.rs\"]\npub(crate) mod x;\n#[path = r\"c.rs\"] pub mod y;\nmod z;";
+    let decls = find_mod_decls(source);
+    assert_eq!(decls.len(), 3);
+    assert_eq!(decls [0].path_attr, Some("a\\b.rs".to_string()));
+    assert_eq!(decls [1].path_attr, Some("c.rs".to_string()));
+    assert_eq!(decls [2].path_attr, None);
+    assert_eq!(unquote("\"\\u{41}\\x42\\\"\""), "AB\"");
+}
+
+#[test]
+fn test_crate_files_go_on() {
+    // tests/ has no crate root, that is reported and the next path is still followed
+    let mut errors = Vec::new();
+    let files = crate_files(& ["tests".to_string(), "src/main.rs".to_string()], &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert!(files.contains(&PathBuf::from("src/files.rs")));
+}
@@ -1,50 +1,382 @@
 #![feature(rustc_private)]
 extern crate syntax;
+extern crate toml;
 
 use std::env;
 use std::fs::File;
-use std::io::Error;
+use std::io;
 use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::panic;
+use std::process;
+use std::sync::{
+    mpsc,
+    Arc,
+    Mutex,
+};
+use std::thread;
+
+use syntax::diagnostic::FatalError;
 
 mod typesetting;
 mod token_handling;
+mod options;
+mod diff;
+mod files;
+mod output;
+mod error;
+mod config;
+mod verify;
+mod exit_code;
+mod common;
 
+use common::print_error;
+use config::{
+    Config,
+    ConfigCache,
+};
+use error::FormatError;
+use exit_code::{
+    EXIT_CHANGES,
+    EXIT_FORMAT_ERROR,
+    EXIT_IO_ERROR,
+    EXIT_USAGE,
+};
+use options::{
+    Emit,
+    Options,
+    print_usage,
+};
 use typesetting::Typesetter;
 
-fn format_file(filename: &str) -> Result < (),
-Error > {
-    let mut f = try!(File::open(filename));
-    let mut source = String::new();
-    try!(f.read_to_string(&mut source));
+// The outcome of formating one file: whether it changed and what has to be printed to stdout for
+// it. Printing is left to the caller, so that the output of parallel jobs stays in order.
+struct Report {
+    changed:bool,
+    output:String,
+}
+
+// Formats the file and reports whether the formated source differs from the file on disk. In
+// check mode, or if nothing changed, the file is left untouched.
+fn format_file(filename: &Path, options: &Options, configs: &ConfigCache) -> Result < Report,
+FormatError > {
+    let mut bytes = Vec::new();
+    try!(File::open(filename).and_then( | mut f | f.read_to_end(&mut bytes)));
+    let source = try!(String::from_utf8(bytes).map_err( | _ | FormatError::NotUtf8));
 
-    f = try!(File::create(filename));
+    let name = filename.display().to_string();
+    let config = try!(configs.for_file(filename));
+    let formated_source = try!(format_source(&name, source.as_ref(), &config));
+    if options.verify_idempotent {
+        try!(check_idempotent(&name, &formated_source, &config, options));
+    }
+    let changed = formated_source != source;
 
-    let typesetter = Typesetter::new(source.as_ref());
-    try!(f.write_all(typesetter.to_string().as_bytes()));
-    Ok(())
+    let mut output = String::new();
+    match options.emit {
+        Emit::Diff => {
+            output = diff::unified_diff(&name, &source, &formated_source, options.color);
+        }
+        Emit::Files => {
+            if changed && !options.check {
+                let backup = options.backup.as_ref().map( | suffix | suffix.as_ref());
+                try!(output::write_atomically(filename, &formated_source, backup));
+            }
+        }
+    }
+    Ok(Report {
+        changed:changed,
+        output:output,
+    })
 }
 
-pub fn main() {
-    let mut args:Vec < String > = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: {} [one or more rust files]", args.first().unwrap());
-        return;
+// Same as format_file, but reads the source from stdin, the result goes to stdout. If the source
+// can't be formated it is written back unchanged, so that an editor or a git filter that pipes
+// through rustformat doesn't lose it.
+fn format_stdin(filename: &str, options: &Options) -> Result < Report,
+FormatError > {
+    let mut bytes = Vec::new();
+    try!(io::stdin().read_to_end(&mut bytes));
+    let result = format_stdin_source(filename, &bytes, options);
+    if result.is_err() && options.emit == Emit::Files && !options.check {
+        let _ = io::stdout().write_all(&bytes);
+    }
+    result
+}
+
+fn format_stdin_source(filename: &str, bytes: & [u8], options: &Options) -> Result < Report,
+FormatError > {
+    let source = try!(String::from_utf8(bytes.to_vec()).map_err( | _ | FormatError::NotUtf8));
+
+    // settings are looked up relative to --stdin-filepath, or the current directory
+    let config = try!(Config::for_file(Path::new(filename), &options.config_overrides));
+    let formated_source = try!(format_source(filename, source.as_ref(), &config));
+    if options.verify_idempotent {
+        try!(check_idempotent(filename, &formated_source, &config, options));
+    }
+    let changed = formated_source != source;
+
+    let mut output = String::new();
+    match options.emit {
+        Emit::Diff => {
+            output = diff::unified_diff(filename, &source, &formated_source, options.color);
+        }
+        Emit::Files => {
+            if !options.check {
+                output = formated_source;
+            }
+        }
+    }
+    Ok(Report {
+        changed:changed,
+        output:output,
+    })
+}
+
+// The typesetter runs on its own thread, so that a bug that panics only fails this file instead
+// of the whole run. The result is only used if it has the same tokens as the source.
+fn format_source(filename: &str, source: &str, config: &Config) -> Result < String,
+FormatError > {
+    let filename = filename.to_string();
+    let source = source.to_string();
+    let config = config.clone();
+    let result = thread::spawn(move || {
+        let typesetter = match Typesetter::new(source.as_ref(), &config) {
+            Ok(typesetter) => typesetter,
+            Err(e) => return Err(FormatError::Lex(filename, e)),
+        };
+        let (formated_source, closers) = typesetter.render();
+        match verify::verify(&source, &formated_source, &closers) {
+            Ok(()) => Ok(formated_source),
+            Err(divergence) => Err(FormatError::Verify(divergence)),
+        }
+    }).join();
+    match result {
+        Ok(result) => result,
+        Err(payload) => Err(FormatError::from_panic(payload)),
+    }
+}
+
+// Formats the formated source once more, the second pass must not change anything.
+fn check_idempotent(name: &str,
+    formated_source: &str,
+    config: &Config,
+    options: &Options) -> Result < (),
+FormatError > {
+    let second_pass = try!(format_source(name, formated_source, config));
+    if second_pass == formated_source {
+        Ok(())
+    } else {
+        let diff = diff::unified_diff(name, formated_source, &second_pass, options.color);
+        Err(FormatError::NotIdempotent(diff))
+    }
+}
+
+// Formats the files on a pool of worker threads. The results are handed to the summary in the
+// order of the files, no matter which job finishes first.
+fn format_files(files: Vec < PathBuf > , options: Arc < Options > , summary: &mut Summary) {
+    let files = Arc::new(files);
+    let configs = Arc::new(ConfigCache::new(&options.config_overrides));
+    let next_file = Arc::new(Mutex::new(0usize));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..options.jobs {
+        let files = files.clone();
+        let next_file = next_file.clone();
+        let options = options.clone();
+        let configs = configs.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            loop {
+                let index = {
+                    let mut next_file = next_file.lock().unwrap();
+                    *next_file += 1;
+                    *next_file - 1
+                };
+                if index >= files.len() {
+                    break;
+                }
+                let result = format_file(&files [index], &options, &configs);
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut results = Vec::new();
+    for _ in 0..files.len() {
+        results.push(None);
+    }
+    let mut next_to_report = 0usize;
+    for message in receiver.iter() {
+        let (index, result) = message;
+        results[index] = Some(result);
+        while next_to_report < files.len() && results[next_to_report].is_some() {
+            let result = results[next_to_report].take().unwrap();
+            summary.add(&files [next_to_report].display().to_string(), result, &options);
+            next_to_report += 1;
+        }
+    }
+    // a job that died outside of format_source never sent the result of its file, which then
+    // counts as not formated, the results after it are still reported
+    while next_to_report < files.len() {
+        let result = match results[next_to_report].take() {
+            Some(result) => result,
+            None => Err(FormatError::Panic("no result, a formating job died".to_string())),
+        };
+        summary.add(&files [next_to_report].display().to_string(), result, &options);
+        next_to_report += 1;
     }
-    args.remove(0);
+}
+
+// Keeps track of what happened to the files, for the summary and the exit code.
+struct Summary {
+    files:usize,
+    changed:usize,
+    format_errors:usize,
+    io_errors:usize,
+}
+
+impl Summary {
+    fn new() -> Summary {
+        Summary {
+            files:0,
+            changed:0,
+            format_errors:0,
+            io_errors:0,
+        }
+    }
+
+    fn add(&mut self, filename: &str, result: Result < Report, FormatError > , options: &Options) {
+        self.files += 1;
+        match result {
+            Err(e) => self.add_error(filename, e),
+            Ok(report) => {
+                print!("{}", report.output);
+                if report.changed {
+                    self.changed += 1;
+                    if options.check {
+                        println!("{}", filename);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_error(&mut self, filename: &str, e: FormatError) {
+        match e {
+            // already starts with the file and the position
+            FormatError::Lex(_, _) => print_error(&format!("{}", e)),
+            _ => print_error(&format!("{}: {}", filename, e)),
+        }
+        if e.is_io() {
+            self.io_errors += 1;
+        } else {
+            self.format_errors += 1;
+        }
+    }
+
+    fn print(&self, options: &Options) {
+        let changed = if options.check || options.emit == Emit::Diff {
+            "need formating"
+        } else {
+            "reformated"
+        };
+        let errors = self.io_errors + self.format_errors;
+        print_error(&format!("{} files, {} {}, {} errors", self.files, self.changed, changed,
+            errors));
+    }
+
+    fn exit_code(&self, options: &Options) -> i32 {
+        if self.io_errors > 0 {
+            EXIT_IO_ERROR
+        } else if self.format_errors > 0 {
+            EXIT_FORMAT_ERROR
+        } else if self.changed > 0 && options.check {
+            EXIT_CHANGES
+        } else {
+            0
+        }
+    }
+}
 
-    for filename in args {
-        match format_file(filename.as_ref()) {
+// --print-config: the defaults, or the settings that apply to the given path (with the
+// overrides from the command line)
+fn print_config(which: &str, options: &Options) -> ! {
+    let config = if which == "default" {
+        Config::default()
+    } else {
+        let path = options.files.first().map_or(".", | path | path.as_ref());
+        match Config::for_file(Path::new(path), &options.config_overrides) {
+            Ok(config) => config,
             Err(e) => {
-                println!("{:?}", e);
-                return;
-            },
-            Ok(_) => {},
+                print_error(&format!("invalid config: {}", e));
+                process::exit(EXIT_FORMAT_ERROR);
+            }
+        }
+    };
+    print!("{}", config.to_toml());
+    process::exit(0);
+}
+
+pub fn main() {
+    // fatal lexer errors unwind with a FatalError after the error was collected, that doesn't need
+    // a "thread panicked" message
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move | info | {
+        if !info.payload().is::< FatalError > () {
+            default_hook(info);
+        }
+    }));
+
+    let args:Vec < String > = env::args().collect();
+    let options = match Options::from_args(&args [1..]) {
+        Ok(options) => options,
+        Err(msg) => {
+            print_error(&msg);
+            print_usage();
+            process::exit(EXIT_USAGE);
         }
+    };
+
+    if let Some(ref which) = options.print_config {
+        print_config(which, &options);
+    }
+
+    let mut summary = Summary::new();
+    if options.stdin {
+        let filename = options.stdin_filepath.clone().unwrap_or("<stdin>".to_string());
+        let result = format_stdin(filename.as_ref(), &options);
+        summary.add(filename.as_ref(), result, &options);
+        process::exit(summary.exit_code(&options));
     }
+
+    // paths that can't be searched are reported like files that can't be read
+    let mut path_errors = Vec::new();
+    let files = if options.crate_tree {
+        files::crate_files(&options.files, &mut path_errors)
+    } else {
+        files::expand_paths(&options.files, &mut path_errors)
+    };
+    for error in path_errors {
+        summary.add_error(&error.0.display().to_string(), FormatError::from(error.1));
+    }
+
+    let options = Arc::new(options);
+    format_files(files, options.clone(), &mut summary);
+    summary.print(&options);
+    process::exit(summary.exit_code(&options));
 }
 
 #[test]
 fn test_cases() {
+    use std::fs;
+
     let paths_to_test = fs::read_dir(&Path::new("tests")).unwrap();
 
     for path_to_test in paths_to_test {
@@ -69,8 +401,19 @@ fn test_cases() {
         let mut f_post = File::open(filename_post).unwrap();
         f_post.read_to_string(&mut source_post).unwrap();
 
-        let typesetter = Typesetter::new(source_pre.as_ref());
+        // a test can come with its own settings
+        let config = if p.join(config::CONFIG_FILE_NAME).is_file() {
+            Config::for_file(&p, & []).unwrap()
+        } else {
+            Config::default()
+        };
+        let typesetter = Typesetter::new(source_pre.as_ref(), &config).unwrap();
 
-        assert_eq!(source_post, typesetter.to_string());
+        let (formated_source, closers) = typesetter.render();
+        assert_eq!(source_post, formated_source);
+        assert!(verify::verify(&source_pre, &source_post, &closers).is_ok());
+        // formating the formated source again must not change it
+        let typesetter = Typesetter::new(source_post.as_ref(), &config).unwrap();
+        assert_eq!(source_post, typesetter.render().0);
     }
 }
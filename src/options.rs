@@ -0,0 +1,158 @@
+use std::io;
+use std::io::prelude::*;
+
+use config::{
+    parse_override,
+    Override,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Emit {
+    Files,  // write the formated source back (or to stdout)
+    Diff,  // print a unified diff, don't write anything
+}
+
+// All the settings that can be given on the command line.
+pub struct Options {
+    // only report files that would change, don't touch them
+    pub check:bool,
+    // read the source from stdin and write the result to stdout
+    pub stdin:bool,
+    // the path that stands in for stdin, e.g. the file open in the editor
+    pub stdin_filepath:Option < String > ,
+    // what to do with the formated source
+    pub emit:Emit,
+    // colorize the diff
+    pub color:bool,
+    // the files are crate roots, format the files of their module trees
+    pub crate_tree:bool,
+    // keep a copy of every file that gets changed, with this suffix added to its name
+    pub backup:Option < String > ,
+    // format every file a second time and fail if that changes anything
+    pub verify_idempotent:bool,
+    // number of files formated in parallel
+    pub jobs:usize,
+    // settings given with --config, they win over rustformat.toml
+    pub config_overrides:Vec < Override > ,
+    // print the config (current or default) instead of formating anything
+    pub print_config:Option < String > ,
+    pub files:Vec < String > ,
+}
+
+impl Options {
+    pub fn from_args(args: & [String]) -> Result < Options,
+    String > {
+        let mut options = Options {
+            check:false,
+            stdin:false,
+            stdin_filepath:None,
+            emit:Emit::Files,
+            color:false,
+            crate_tree:false,
+            backup:None,
+            verify_idempotent:false,
+            jobs:1,
+            config_overrides:Vec::new(),
+            print_config:None,
+            files:Vec::new(),
+        };
+        let mut args = args.iter();
+        loop {
+            let arg = match args.next() {
+                Some(arg) => arg,
+                None => break,
+            };
+            if arg == "--check" {
+                options.check = true;
+            } else if arg == "--backup" {
+                options.backup = Some(try!(value_of(arg, args.next())));
+            } else if arg == "-j" || arg == "--jobs" {
+                options.jobs = try!(value_of(arg, args.next())).parse().unwrap_or(0);
+                if options.jobs == 0 {
+                    return Err(format!("`{}` expects a number greater than zero", arg));
+                }
+            } else if arg == "--config" {
+                let value = try!(value_of(arg, args.next()));
+                options.config_overrides.push(try!(parse_override(&value)));
+            } else if arg == "--print-config" {
+                let value = try!(value_of(arg, args.next()));
+                if value != "current" && value != "default" {
+                    return Err(format!("`--print-config` expects current or default, found `{}`",
+                        value));
+                }
+                options.print_config = Some(value);
+            } else if arg == "--verify-idempotent" {
+                options.verify_idempotent = true;
+            } else if arg == "--crate" {
+                options.crate_tree = true;
+            } else if arg == "-" || arg == "--stdin" {
+                options.stdin = true;
+            } else if arg == "--stdin-filepath" {
+                options.stdin_filepath = Some(try!(value_of(arg, args.next())));
+            } else if arg == "--emit" {
+                options.emit = match try!(value_of(arg, args.next())).as_ref() {
+                    "files" => Emit::Files,
+                    "diff" => Emit::Diff,
+                    other => return Err(format!("unknown emit mode `{}`", other)),
+                };
+            } else if arg == "--color" {
+                options.color = match try!(value_of(arg, args.next())).as_ref() {
+                    "always" => true,
+                    "never" => false,
+                    other => return Err(format!("unknown color setting `{}`", other)),
+                };
+            } else if arg.starts_with("-") {
+                return Err(format!("unknown option `{}`", arg));
+            } else {
+                options.files.push(arg.clone());
+            }
+        }
+
+        if options.print_config.is_some() {
+            // the files are only used to find the config
+            if options.files.len() > 1 {
+                return Err("`--print-config` takes at most one path".to_string());
+            }
+            return Ok(options);
+        }
+        if options.stdin_filepath.is_some() {
+            options.stdin = true;
+        }
+        if options.stdin && !options.files.is_empty() {
+            return Err("files can't be given together with stdin".to_string());
+        }
+        if !options.stdin && options.files.is_empty() {
+            return Err("no input files".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn value_of(option: &str, value: Option < &String > ) -> Result < String,
+String > {
+    match value {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("option `{}` requires a value", option)),
+    }
+}
+
+// printed after a usage error, so it goes to stderr like the error
+pub fn print_usage() {
+    let usage = ["Usage: rustformat [options] [one or more rust files or directories]", "",
+        "Options:", "    --check                  list files that would change, don't touch them",
+        "    --crate                  format the module trees of the given crate roots",
+        "    -, --stdin               format stdin and write the result to stdout",
+        "    --stdin-filepath <path>  path of the file that is piped into stdin",
+        "    --emit files|diff        write the formated files or print a unified diff",
+        "    --color always|never     colorize the diff",
+        "    --backup <suffix>        copy changed files to <name><suffix> first",
+        "    --verify-idempotent      fail if a second pass changes the formated source",
+        "    -j, --jobs <n>           format <n> files in parallel",
+        "    --config <key>=<value>   override a setting of rustformat.toml",
+        "    --print-config current|default [path]",
+        "                             print the settings that apply to path (default: .)"];
+    let mut stderr = io::stderr();
+    for line in usage.iter() {
+        let _ = writeln!(&mut stderr, "{}", line);
+    }
+}
@@ -0,0 +1,107 @@
+// Writes the formated files back to disk.
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+    ATOMIC_USIZE_INIT,
+};
+
+// the number of temporary directories tried so far
+static TMP_DIRS:AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Writes the content to a temporary file next to the original and renames it over the original,
+// so that the file is never left half written. The permissions of the original are kept. With a
+// backup suffix the original is copied to <name><suffix> first.
+pub fn write_atomically(filename: &Path,
+    content: &str,
+    backup: Option < &str > ) -> Result < (),
+Error > {
+    // write through symlinks instead of replacing them
+    let target = try!(fs::canonicalize(filename));
+    let permissions = try!(fs::metadata(&target)).permissions();
+    let tmp_dir = try!(create_tmp_dir(&target));
+    let tmp = tmp_dir.join(target.file_name().unwrap_or(target.as_os_str()));
+
+    let result = write_and_rename(&target, &tmp, content, permissions, backup);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    let _ = fs::remove_dir(&tmp_dir);
+    result
+}
+
+// Creates a directory next to the target that belongs to this write alone. Unlike creating a
+// file, creating a directory fails if it exists, so another job or another rustformat process
+// writing the same file makes us take the next number instead of truncating its file.
+fn create_tmp_dir(target: &Path) -> Result < PathBuf,
+Error > {
+    loop {
+        let number = TMP_DIRS.fetch_add(1, Ordering::SeqCst);
+        let dir = sibling_path(target, ".", &format!(".rustformat-tmp-{}", number));
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) => {
+                if e.kind() != ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+fn write_and_rename(target: &Path,
+    tmp: &Path,
+    content: &str,
+    permissions: fs::Permissions,
+    backup: Option < &str > ) -> Result < (),
+Error > {
+    {
+        let mut f = try!(File::create(tmp));
+        try!(f.write_all(content.as_bytes()));
+        try!(f.sync_all());
+    }
+    try!(fs::set_permissions(tmp, permissions));
+    if let Some(suffix) = backup {
+        try!(fs::copy(target, sibling_path(target, "", suffix)));
+    }
+    fs::rename(tmp, target)
+}
+
+// foo/bar.rs -> foo/<prefix>bar.rs<suffix>
+fn sibling_path(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(prefix);
+    name.push(path.file_name().unwrap_or(path.as_os_str()));
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[test]
+fn test_tmp_dir_in_use() {
+    use std::env;
+    let dir = env::temp_dir().join("rustformat-test-output");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let file = dir.join("a.rs");
+    File::create(&file).unwrap().write_all(b"old").unwrap();
+    // another rustformat process is writing the same file
+    let taken = dir.join(".a.rs.rustformat-tmp-0");
+    fs::create_dir(&taken).unwrap();
+
+    write_atomically(&file, "new", None).unwrap();
+    let mut content = String::new();
+    File::open(&file).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "new");
+    assert!(taken.is_dir());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,3 +1,16 @@
+use syntax::codemap::{
+    CodeMap,
+    Span,
+};
+use syntax::diagnostic::{
+    Emitter,
+    Handler,
+    Level,
+    RenderSpan,
+    SpanHandler,
+};
+use syntax::parse;
+use syntax::parse::ParseSess;
 use syntax::parse::lexer::{
     StringReader,
     TokenAndSpan,
@@ -5,39 +18,201 @@ use syntax::parse::lexer::{
 };
 use syntax::parse::token;
 use syntax::parse::token::Token;
+use std::fmt;
 use std::iter;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::thread;
+
+// A problem the lexer found in the source, e.g. an unterminated string.
+#[derive(Debug, Clone)]
+pub struct LexError {
+    pub line:usize,
+    pub column:usize,
+    pub message:String,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+// Collects the errors of the lexer instead of printing them.
+struct CollectingEmitter {
+    errors:Arc < Mutex < Vec < LexError >> > ,
+}
+
+impl Emitter for CollectingEmitter {
+    fn emit(&mut self,
+        cmsp: Option < (&CodeMap, Span) > ,
+        msg: &str,
+        _: Option < &str > ,
+        lvl: Level) {
+        if lvl != Level::Fatal && lvl != Level::Error {
+            return;
+        }
+        let (line, column) = match cmsp {
+            Some((cm, span)) => {
+                let loc = cm.lookup_char_pos(span.lo);
+                (loc.line, loc.col.0 + 1)
+            }
+            None => (0, 0),
+        };
+        self.errors.lock().unwrap().push(LexError {
+            line:line,
+            column:column,
+            message:msg.to_string(),
+        });
+    }
+
+    fn custom_emit(&mut self, _: &CodeMap, _: RenderSpan, msg: &str, lvl: Level) {
+        self.emit(None, msg, None, lvl);
+    }
+}
+
+// Runs the lexer over the whole source.
+pub fn lex(source: &str) -> Result < Vec < Word > ,
+LexError > {
+    let words = try!(lex_spanned(source));
+    Ok(words.into_iter().map( | spanned | spanned.word).collect())
+}
+
+// Same as lex, but every word keeps its position in the source.
+pub fn lex_spanned(source: &str) -> Result < Vec < SpannedWord > ,
+LexError > {
+    run_lexer(source, put_tokens_into_vec)
+}
+
+// What verify needs to know of a token besides its text, the token itself can't leave the thread
+// of the lexer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Whitespace,
+    Comment,
+    DocComment,
+    Shebang,
+    Other,
+}
+
+impl TokenKind {
+    fn of(token: &Token) -> TokenKind {
+        match *token {
+            token::Whitespace => TokenKind::Whitespace,
+            token::Comment => TokenKind::Comment,
+            token::DocComment(_) => TokenKind::DocComment,
+            token::Shebang(_) => TokenKind::Shebang,
+            _ => TokenKind::Other,
+        }
+    }
+}
+
+// A token as the lexer read it: its kind, its text and the byte offset it starts at.
+#[derive(Debug, Clone)]
+pub struct RawToken {
+    pub kind:TokenKind,
+    pub text:String,
+    pub start:usize,
+}
+
+// Runs the lexer over the whole source and keeps the tokens as they are, without reducing them to
+// words. Only the end of the file is left out.
+pub fn lex_raw(source: &str) -> Result < Vec < RawToken > ,
+LexError > {
+    run_lexer(source, | lexer, lexed | {
+        let mut tokens = Vec::new();
+        loop {
+            let next = lexer.next_token();
+            if next.tok == token::Eof {
+                break;
+            }
+            lexed.store(next.sp.hi.0 as usize, Ordering::SeqCst);
+            tokens.push(RawToken {
+                kind:TokenKind::of(&next.tok),
+                text:lexer.span_diagnostic.cm.span_to_snippet(next.sp).unwrap(),
+                start:next.sp.lo.0 as usize,
+            });
+        }
+        tokens
+    })
+}
+
+// Hands a lexer over the source to f and collects the errors it reports. The lexer runs on a
+// thread of its own, fatal errors unwind out of it and only end that thread. f keeps track of how
+// far the lexer got in lexed, so that an error without a message still has a position.
+fn run_lexer < T,
+F > (source: &str, f: F) -> Result < T,
+LexError >
+where T:Send + 'static,
+F:FnOnce(&mut StringReader, &AtomicUsize) -> T + Send + 'static {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let lexed = Arc::new(AtomicUsize::new(0));
+    let emitter = CollectingEmitter {
+        errors:errors.clone(),
+    };
+    let thread_lexed = lexed.clone();
+    let thread_source = source.to_string();
+    let result = thread::spawn(move || {
+        let handler = Handler::with_emitter(true, Box::new(emitter));
+        let session = ParseSess::with_span_handler(SpanHandler::new(handler, CodeMap::new()));
+        let filemap = parse::string_to_filemap(&session, thread_source, "<source>".to_string());
+        let mut lexer = StringReader::new(&session.span_diagnostic, filemap);
+        f(&mut lexer, &thread_lexed)
+    }).join();
+    let first_error = errors.lock().unwrap().first().cloned();
+    match (result, first_error) {
+        (Ok(tokens), None) => Ok(tokens),
+        (_, Some(error)) => Err(error),
+        (Err(_), None) => {
+            let (line, column) = line_and_column(source, lexed.load(Ordering::SeqCst));
+            Err(LexError {
+                line:line,
+                column:column,
+                message:"the lexer failed".to_string(),
+            })
+        }
+    }
+}
 
-pub fn put_tokens_into_vec(lexer: &mut StringReader) -> Vec < Word > {
+pub fn put_tokens_into_vec(lexer: &mut StringReader, lexed: &AtomicUsize) -> Vec < SpannedWord > {
     let mut tokens = Vec::new();
     loop {
         match lexer.next_token() {
             TokenAndSpan {
                 tok:token::Eof,
-                sp:_,
+                sp:span,
             } => {
-                tokens.push(Word::Eof);
+                tokens.push(SpannedWord::new(Word::Eof, span));
                 break;
             },
             TokenAndSpan {
                 tok:token,
                 sp:span,
             } => {
-                let word = Word::from_token(token);
+                lexed.store(span.hi.0 as usize, Ordering::SeqCst);
+                let snippet = lexer.span_diagnostic.cm.span_to_snippet(span).unwrap();
+                let word = Word::from_token(token, snippet.clone());
                 match word {
-                    Word::Nope => {},
+                    Word::Nope => {
+                        // never drop code, the error keeps the file from being written
+                        let msg = format!("rustformat can't handle the token `{}`", snippet);
+                        lexer.span_diagnostic.span_err(span, &msg);
+                    },
                     Word::Whitespace(_) => {
-                        let s = lexer.span_diagnostic.cm.span_to_snippet(span).unwrap();
-                        if s.contains("\n\n") {
-                            tokens.push(Word::LineBreakDouble);
-                        } else if s.contains("\n") {
-                            tokens.push(Word::LineBreak);
+                        // counted, so that \r\n\r\n is a blank line as well
+                        if snippet.matches('\n').count() > 1 {
+                            tokens.push(SpannedWord::new(Word::LineBreakDouble, span));
+                        } else if snippet.contains("\n") {
+                            tokens.push(SpannedWord::new(Word::LineBreak, span));
                         }
                     },
-                    Word::Comment(_) => {
-                        let s = lexer.span_diagnostic.cm.span_to_snippet(span).unwrap();
-                        tokens.push(Word::Comment(s));
-                    },
-                    _ => tokens.push(word),
+                    _ => tokens.push(SpannedWord::new(word, span)),
                 }
             },
         }
@@ -45,6 +220,39 @@ pub fn put_tokens_into_vec(lexer: &mut StringReader) -> Vec < Word > {
     tokens
 }
 
+// A word together with the span of the source it was read from.
+#[derive(Debug, Clone)]
+pub struct SpannedWord {
+    pub word:Word,
+    pub span:Span,
+}
+
+impl SpannedWord {
+    fn new(word: Word, span: Span) -> SpannedWord {
+        SpannedWord {
+            word:word,
+            span:span,
+        }
+    }
+
+    // byte offset of the start of the word in the source
+    pub fn start(&self) -> usize {
+        self.span.lo.0 as usize
+    }
+
+    // byte offset just after the end of the word
+    pub fn end(&self) -> usize {
+        self.span.hi.0 as usize
+    }
+}
+
+// the line and column (both starting at 1) of a byte position in the source
+pub fn line_and_column(source: &str, pos: usize) -> (usize, usize) {
+    let before = &source[..pos];
+    let line_start = before.rfind('\n').map_or(0, | i | i + 1);
+    (before.matches('\n').count() + 1, before [line_start..].chars().count() + 1)
+}
+
 // A word is a token reduced to all the information the pretty printing process requires. The
 // different types of are not necessarily the same as the token types.
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -58,6 +266,8 @@ pub enum Word {
     SlimInfix(String),
     // everything else, no need for disctiontion for the typesetting process.
     Other(String),
+    // 'a, 'static or a loop label like 'outer
+    Lifetime(String),
     Comment(String),
     LineBreak,
     LineBreakDouble,
@@ -73,6 +283,16 @@ pub enum Word {
     CloseParen,
     OpenBrace,
     CloseBrace,
+    // the | | around the parameters of a closure, the lexer only knows the binary |
+    OpenBar,
+    CloseBar,
+    // the ] that ends an attribute
+    CloseAttribute,
+    // the ) that ends a $(...) repetition in macro_rules!
+    CloseRepetition,
+    // the lines of a macro body that doesn't look like Rust, written back as they are
+    Verbatim(Vec < String > ),
+    // a token rustformat doesn't know how to set, it becomes a lexer error
     Nope,
     Eof,
 }
@@ -83,16 +303,19 @@ impl Word {
             Word::BinaryOperator(s) => s,
             Word::PrefixOperator(s) => s,
             Word::OpenParen => "(".to_string(),
-            Word::CloseParen => ")".to_string(),
+            Word::CloseParen | Word::CloseRepetition => ")".to_string(),
             Word::OpenBrace => "{".to_string(),
             Word::CloseBrace => "}".to_string(),
             Word::OpenBracket => "[".to_string(),
-            Word::CloseBracket => "]".to_string(),
+            Word::CloseBracket | Word::CloseAttribute => "]".to_string(),
+            Word::OpenBar | Word::CloseBar => "|".to_string(),
             Word::SemiColon => ";".to_string(),
             Word::Comma => ",".to_string(),
             Word::Colon => ":".to_string(),
             Word::SlimInfix(s) => s,
             Word::Other(s) => s,
+            Word::Lifetime(s) => s,
+            Word::Verbatim(lines) => lines.join("\n"),
             Word::Comment(s) => convert_comment(s.as_ref()).to_string(),
             Word::LineBreak | Word::LineBreakIntentPlus | Word::LineBreakIntentMinus =>
             "\n".to_string(),
@@ -109,58 +332,61 @@ impl Word {
     }
 
     // This functions is mapping tokens to words.
-    // Sorry for this gigantic monster of a match statement.
-    fn from_token(token: Token) -> Word {
+    // Sorry for this gigantic monster of a match statement. It has no catch-all arm on purpose, a
+    // new kind of token has to be mapped before rustformat builds again.
+    // The text is the snippet of the token in the source, so that every word is written exactly as
+    // it was read.
+    fn from_token(token: Token, text: String) -> Word {
         match token {
-            token::Eq => Word::BinaryOperator("=".to_string()),
-            token::EqEq => Word::BinaryOperator("==".to_string()),
-            token::Ne => Word::BinaryOperator("!=".to_string()),
-            token::Ge => Word::BinaryOperator(">=".to_string()),
-            token::Gt => Word::BinaryOperator(">".to_string()),
-            token::Le => Word::BinaryOperator("<=".to_string()),
-            token::Lt => Word::BinaryOperator("<".to_string()),
-            token::AndAnd => Word::BinaryOperator("&&".to_string()),
-            token::OrOr => Word::BinaryOperator("||".to_string()),
-            token::Not => Word::PrefixOperator("!".to_string()),
-            token::Tilde => Word::PrefixOperator("~".to_string()),
+            token::Eq => Word::BinaryOperator(text),
+            token::EqEq => Word::BinaryOperator(text),
+            token::Ne => Word::BinaryOperator(text),
+            token::Ge => Word::BinaryOperator(text),
+            token::Gt => Word::BinaryOperator(text),
+            token::Le => Word::BinaryOperator(text),
+            token::Lt => Word::BinaryOperator(text),
+            token::AndAnd => Word::BinaryOperator(text),
+            token::OrOr => Word::BinaryOperator(text),
+            token::Not => Word::PrefixOperator(text),
+            token::Tilde => Word::PrefixOperator(text),
             token::BinOp(bin_op_token) =>
             match bin_op_token {
-                token::Plus => Word::BinaryOperator("+".to_string()),
-                token::Minus => Word::BinaryOperator("-".to_string()),
-                token::Star => Word::BinaryOperator("*".to_string()),
-                token::Slash => Word::BinaryOperator("/".to_string()),
-                token::Percent => Word::BinaryOperator("%".to_string()),
-                token::Caret => Word::BinaryOperator("^".to_string()),
-                token::And => Word::PrefixOperator("&".to_string()),
-                token::Or => Word::BinaryOperator("|".to_string()),
-                token::Shl => Word::BinaryOperator("<<".to_string()),
-                token::Shr => Word::BinaryOperator(">>".to_string()),
+                token::Plus => Word::BinaryOperator(text),
+                token::Minus => Word::BinaryOperator(text),
+                token::Star => Word::BinaryOperator(text),
+                token::Slash => Word::BinaryOperator(text),
+                token::Percent => Word::BinaryOperator(text),
+                token::Caret => Word::BinaryOperator(text),
+                token::And => Word::PrefixOperator(text),
+                token::Or => Word::BinaryOperator(text),
+                token::Shl => Word::BinaryOperator(text),
+                token::Shr => Word::BinaryOperator(text),
             },
             token::BinOpEq(bin_op_token) =>
             match bin_op_token {
-                token::Plus => Word::BinaryOperator("+=".to_string()),
-                token::Minus => Word::BinaryOperator("-=".to_string()),
-                token::Star => Word::BinaryOperator("*=".to_string()),
-                token::Slash => Word::BinaryOperator("/=".to_string()),
-                token::Percent => Word::BinaryOperator("%=".to_string()),
-                token::Caret => Word::BinaryOperator("^=".to_string()),
-                token::And => Word::BinaryOperator("&=".to_string()),
-                token::Or => Word::BinaryOperator("|=".to_string()),
-                token::Shl => Word::BinaryOperator("<<=".to_string()),
-                token::Shr => Word::BinaryOperator(">>=".to_string()),
+                token::Plus => Word::BinaryOperator(text),
+                token::Minus => Word::BinaryOperator(text),
+                token::Star => Word::BinaryOperator(text),
+                token::Slash => Word::BinaryOperator(text),
+                token::Percent => Word::BinaryOperator(text),
+                token::Caret => Word::BinaryOperator(text),
+                token::And => Word::BinaryOperator(text),
+                token::Or => Word::BinaryOperator(text),
+                token::Shl => Word::BinaryOperator(text),
+                token::Shr => Word::BinaryOperator(text),
             },
-            token::At => Word::PrefixOperator("@".to_string()),
-            token::Dot => Word::SlimInfix(".".to_string()),
-            token::DotDot => Word::SlimInfix("..".to_string()),
-            token::DotDotDot => Word::SlimInfix("...".to_string()),
-            token::ModSep => Word::SlimInfix("::".to_string()),
+            token::At => Word::PrefixOperator(text),
+            token::Dot => Word::SlimInfix(text),
+            token::DotDot => Word::SlimInfix(text),
+            token::DotDotDot => Word::SlimInfix(text),
+            token::ModSep => Word::SlimInfix(text),
             token::Comma => Word::Comma,
             token::Semi => Word::SemiColon,
             token::Colon => Word::Colon,
 
-            token::RArrow => Word::BinaryOperator("->".to_string()),
-            token::LArrow => Word::Other("<-".to_string()),
-            token::FatArrow => Word::BinaryOperator("=>".to_string()),
+            token::RArrow => Word::BinaryOperator(text),
+            token::LArrow => Word::Other(text),
+            token::FatArrow => Word::BinaryOperator(text),
 
             token::OpenDelim(token::Paren) => Word::OpenParen,
             token::CloseDelim(token::Paren) => Word::CloseParen,
@@ -169,47 +395,37 @@ impl Word {
             token::OpenDelim(token::Brace) => Word::OpenBrace,
             token::CloseDelim(token::Brace) => Word::CloseBrace,
 
-            token::Pound => Word::PrefixOperator("#".to_string()),
-            token::Dollar => Word::PrefixOperator("$".to_string()),
-            token::Question => Word::PrefixOperator("?".to_string()),
-            token::Literal(lit, suf) => {
-                let mut out = match lit {
-                    token::Byte(b) => format!("b'{}'", b.as_str()),
-                    token::Char(c) => format!("'{}'", c.as_str()),
-                    token::Float(c) => c.as_str().to_string(),
-                    token::Integer(c) => c.as_str().to_string(),
-                    token::Str_(s) => format!("\"{}\"", s.as_str()),
-                    token::StrRaw(s, n) => format!("r{delim}\"{string}\"{delim}",
-                        delim = repeat("#", n),
-                        string = s.as_str()),
-                    token::Binary(v) => format!("b\"{}\"", v.as_str()),
-                    token::BinaryRaw(s, n) => format!("br{delim}\"{string}\"{delim}",
-                        delim = repeat("#", n),
-                        string = s.as_str()),
-                };
-                if let Some(s) = suf {
-                    out.push_str(s.as_str())
-                }
-                Word::Other(out)
-            },
-            token::Ident(s, _) => {
-                let s = token::get_ident(s).to_string();
-                if s == "as" {
-                    Word::BinaryOperator(s)
+            token::Pound => Word::PrefixOperator(text),
+            token::Dollar => Word::PrefixOperator(text),
+            token::Question => Word::PrefixOperator(text),
+            // literals are taken as they are written, with their escapes, suffixes and raw string
+            // delimiters
+            token::Literal(_, _) => Word::Other(text),
+            token::Ident(_, _) => {
+                if text == "as" {
+                    Word::BinaryOperator(text)
                 } else {
-                    Word::Other(s)
+                    Word::Other(text)
                 }
             }
-            token::Lifetime(s) => Word::Other(format!("{}", token::get_ident(s))),  // ???
-            token::Underscore => Word::Other("_".to_string()),
+            token::Lifetime(_) => Word::Lifetime(text),
+            token::Underscore => Word::Other(text),
             // not alle whitespaces are linebreaks, but we decide that in fn put_tokens_into_vec
             token::Whitespace => Word::Whitespace(0),
-            token::DocComment(s) => Word::Comment(s.to_string()),
-            token::Comment => Word::Comment("".to_string()),
+            // a line comment keeps the \r of a \r\n line ending, the typesetter adds its own
+            token::DocComment(_) | token::Comment => {
+                Word::Comment(text.trim_right_matches('\r').to_string())
+            }
+            // shebang lines are only allowed at the very start of the file
+            token::Shebang(_) => Word::Other(text),
+            // these only show up in the token trees of macro expansion, not in source files
+            token::Interpolated(_)
+            | token::MatchNt(_, _, _, _)
+            | token::SubstNt(_, _)
+            | token::SpecialVarNt(_) => Word::Nope,
             token::Eof => {
                 unreachable!()
             },
-            _ => Word::Nope,
         }
     }
 }
@@ -228,3 +444,30 @@ fn convert_comment(comment: &str) -> &str {
         comment
     }
 }
+
+#[test]
+fn test_lex_errors() {
+    let error = lex("fn main() {\n    let s = \"abc;\n}\n").unwrap_err();
+    assert_eq!((error.line, error.column), (2, 13));
+    assert_eq!(error.message, "unterminated double quote string");
+    assert!(lex("let s = \"\\q\";").is_err());
+    assert!(lex("let s = \"abc\";").is_ok());
+}
+
+#[test]
+fn test_literals_verbatim() {
+    let literals = ["b'\\x7f'",
+        "'\\u{1F600}'",
+        "1_000u32",
+        "0x1Fi64",
+        "1e-3f64",
+        "r##\"a\"#b\"##",
+        "br#\"x\"#",
+        "b\"\\n\"",
+        "\"\\t\\\"\""];
+    let source = format!("foo({});", literals.join(", "));
+    let words = lex(&source).unwrap();
+    for literal in literals.iter() {
+        assert!(words.contains(&Word::Other(literal.to_string())), "{} changed", literal);
+    }
+}
@@ -1,16 +1,19 @@
+use config::Config;
 use token_handling::{
-    put_tokens_into_vec,
+    lex_spanned,
+    LexError,
+    SpannedWord,
     Word,
 };
-use syntax::parse;
-use syntax::parse::lexer;
-
-static SPACES_PER_TAP:i32 = 4;
-static MAX_LINE_LENGTH:i32 = 100;
-static MAX_INTENT:i32 = 80;
 
 pub struct Typesetter {
-    words:Box < Vec < Word >>
+    // a byte order mark and a shebang line, they are written back as they are (only the line
+    // ending follows newline_style)
+    preamble:String,
+    // the line ending newline_style resolves to for this source
+    newline: &'static str,
+    words:Box < Vec < Word >> ,
+    config:Config,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -18,24 +21,44 @@ enum Context {
     CodeBlock,  // {...}
     CurlyList,  // {foo, bar}
     List,  // (...), [...] or <...>
-    ListExploded  // same as list, but formated like a codeblock
+    ListExploded,  // same as list, but formated like a codeblock
+    MacroRules,  // macro_rules! foo {...}, the rules are set like statements
 }
 
 impl Typesetter {
-    pub fn new(source: &str) -> Typesetter {
-        let session = parse::new_parse_sess();
-        let filemap = parse::string_to_filemap(&session, source.to_string(), "<stdin>".to_string());
-        let mut lexer = lexer::StringReader::new(&session.span_diagnostic, filemap);
+    pub fn new(source: &str, config: &Config) -> Result < Typesetter,
+    LexError > {
+        let (preamble, rest) = split_preamble(source);
+        let newline = config.newline_style.line_ending(source);
+        // only the rest is lexed, its lines start after the ones of the preamble
+        let words = try!(lex_spanned(rest).map_err( | mut e | {
+            e.line += preamble.matches('\n').count();
+            e
+        }));
+        // the line ending of the shebang follows newline_style like all the others
+        let preamble = if preamble.ends_with("\n") {
+            format!("{}{}", preamble.trim_right_matches('\n').trim_right_matches('\r'), newline)
+        } else {
+            preamble.to_string()
+        };
         let mut typesetter = Typesetter {
-            words:Box::new(put_tokens_into_vec(&mut lexer)),
+            preamble:preamble,
+            newline:newline,
+            words:Box::new(set_aside_macro_bodies(rest, words, config)),
+            config:config.clone(),
         };
         if typesetter.words.len() > 2 {
             typesetter.filter_linebreaks();
+            // the > that close generics end an operand and an attribute is followed by one, so
+            // they are sorted out first
+            typesetter.sort_out_generics();
+            typesetter.sort_out_attributes();
             typesetter.sort_out_ambiguities();
+            typesetter.sort_out_repetitions();
             typesetter.format();
             typesetter.handle_overlong_lines();
         }
-        typesetter
+        Ok(typesetter)
     }
 
     fn filter_linebreaks(&mut self) {
@@ -113,78 +136,178 @@ impl Typesetter {
         self.words = Box::new(result);
     }
 
+    // Decides whether &, &&, *, - and ! are unary or binary. Whether an operand or an operator is
+    // expected is tracked from word to word, which works the same in expressions, patterns and
+    // types: &x, -1, *p and !b stand where an operand is expected, a & b and a - b don't.
     fn sort_out_ambiguities(&mut self) {
         let mut result = Vec::new();
-        let mut index = 1usize;
-        //let mut is_expr = true;
+        let mut expects_operand = true;
+        let mut prev = Word::Nope;
+        // the index of the | that ends the parameters of the closure at hand
+        let mut closing_bar = None;
+        let mut index = 0usize;
 
-        if self.words.len() > 0 {
-            result.push(self.words [0].clone())
+        loop {
+            if index >= self.words.len() {
+                break;
+            }
+            let word = &self.words[index];
+            // macro_rules! is one word, the name of the macro follows it
+            if *word == Word::Other("macro_rules".to_string())
+            && self.words.get(index + 1) == Some(&Word::PrefixOperator("!".to_string())) {
+                prev = Word::Other("macro_rules!".to_string());
+                expects_operand = false;
+                result.push(prev.clone());
+                index += 2;
+                continue;
+            }
+            let opens_closure = *word == Word::BinaryOperator("|".to_string()) && expects_operand;
+            let params_end = if opens_closure {
+                closure_params_end(&self.words, index)
+            } else {
+                None
+            };
+
+            let sorted = if closing_bar == Some(index) {
+                closing_bar = None;
+                Word::CloseBar
+            } else if params_end.is_some() {
+                closing_bar = params_end;
+                Word::OpenBar
+            } else if *word == Word::BinaryOperator("||".to_string()) && expects_operand {
+                // a closure without parameters
+                result.push(Word::OpenBar);
+                Word::CloseBar
+            } else if *word == Word::Colon && ends_with_fragment_name(&result) {
+                Word::SlimInfix(":".to_string())
+            } else {
+                match *word {
+                    Word::PrefixOperator(ref s)
+                    | Word::BinaryOperator(ref s) => {
+                        sort_out_operator(word, s, &prev, expects_operand)
+                    }
+                    _ => word.clone(),
+                }
+            };
+            if !is_layout(&sorted) {
+                expects_operand = expects_operand_after(&sorted, expects_operand);
+                prev = sorted.clone();
+            }
+            result.push(sorted);
+            index += 1;
         }
+        self.words = Box::new(result);
+    }
+
+    // Tells the angle brackets of generics apart from comparisons. A < that can start generics
+    // (after a type name, ::, impl, the name of a fn or type, or where an operand is expected) and
+    // has a matching > is turned into a SlimInfix, and so are the > and >> (two generics closed at
+    // once) that close it. The = of a >= or >>= that closes generics is split off, as in
+    // Option<u8> = None. format() opens a List context for them, so they are set tight.
+    fn sort_out_generics(&mut self) {
+        let mut result = Vec::new();
+        // the number of generics that are open at the current word
+        let mut depth = 0usize;
+        let mut index = 0usize;
 
         loop {
             if index >= self.words.len() {
                 break;
             }
-            let prev = self.words[index - 1].clone();
             let word = self.words[index].clone();
-
-            /*if word == Word::Colon
-            || word == Word::BinaryOperator("->".to_string())
-            || word == Word::Other("fn".to_string())
-            || word == Word::Other("enum".to_string())
-            || word == Word::Other("struct".to_string())
-            || word == Word::Other("impl".to_string()) {
-                is_expr = false;
-            } else if word == Word::SemiColon || word == Word::OpenBrace {
-                is_expr = true;
-            }*/
-
-            // heuristic for decision: dereference operator or muliplication
-            match (prev, word) {
-                (Word::BinaryOperator(_), Word::BinaryOperator(s))
-                | (Word::OpenBracket, Word::BinaryOperator(s))
-                | (Word::OpenParen, Word::BinaryOperator(s))
-                | (Word::OpenBrace, Word::BinaryOperator(s))
-                | (Word::LineBreak, Word::BinaryOperator(s))
-                | (Word::LineBreakDouble, Word::BinaryOperator(s))
-                | (Word::SemiColon, Word::BinaryOperator(s))
-                | (Word::Comma, Word::BinaryOperator(s)) => {
-                    if s == "*" {
-                        result.push(Word::PrefixOperator("*".to_string()));
+            match word {
+                Word::BinaryOperator(ref s) => {
+                    if *s == "<" && is_generic_open(&self.words, index)
+                    && has_generic_close(&self.words, index, depth) {
+                        depth += 1;
+                        result.push(Word::SlimInfix(s.clone()));
+                    } else if closed_generics(s) > 0 && closed_generics(s) <= depth {
+                        depth -= closed_generics(s);
+                        let closer = s.trim_right_matches('=');
+                        result.push(Word::SlimInfix(closer.to_string()));
+                        if closer.len() < s.len() {
+                            result.push(Word::BinaryOperator("=".to_string()));
+                        }
                     } else {
-                        result.push(self.words [index].clone());
+                        result.push(word.clone());
                     }
                 }
-                (Word::Other(w),
-                    Word::PrefixOperator(p)) => {
-                    if w != "if" && p == "!" {
-                        result.push(Word::SlimInfix("!".to_string()));
+                _ => result.push(word.clone()),
+            }
+            index += 1;
+        }
+        self.words = Box::new(result);
+    }
+
+    // Marks the ] that closes an attribute, #[...] or #![...], so that the attribute is set as a
+    // unit: on a line of its own in blocks, followed by a space in lists like fn parameters.
+    fn sort_out_attributes(&mut self) {
+        let mut result = Vec::new();
+        // the brackets that are open, true for the ones that open an attribute
+        let mut brackets = Vec::new();
+
+        for word in self.words.iter() {
+            match *word {
+                Word::OpenBracket => {
+                    brackets.push(opens_attribute(&result));
+                    result.push(word.clone());
+                }
+                Word::CloseBracket => {
+                    if brackets.pop().unwrap_or(false) {
+                        result.push(Word::CloseAttribute);
                     } else {
-                        result.push(self.words [index].clone());
+                        result.push(word.clone());
                     }
                 }
-                (Word::Other(w),
-                    Word::BinaryOperator(b)) => {
-                    if (w == "match" || w == "for" || w == "if" || w == "in" || w == "as")
-                    && b == "*" {
-                        result.push(Word::PrefixOperator("*".to_string()));
-                        // } else if (b == ">" || b == "<") && !is_expr {
-                        //     result.push(Word::SlimInfix(b));
-                    } else {
-                        result.push(self.words [index].clone());
+                _ => result.push(word.clone()),
+            }
+        }
+        self.words = Box::new(result);
+    }
+
+    // In the rules of a macro_rules! definition, the ) that closes a $( becomes a CloseRepetition
+    // and the separator and the operator after it are merged into one word, so that $(...),* is
+    // written as it was.
+    fn sort_out_repetitions(&mut self) {
+        let mut result = Vec::new();
+        let mut index = 0usize;
+        // the number of open delimiters, and that number outside of the rules at hand
+        let mut depth = 0usize;
+        let mut rules_depth = None;
+
+        loop {
+            if index >= self.words.len() {
+                break;
+            }
+            let word = self.words[index].clone();
+            match word {
+                Word::OpenBrace | Word::OpenBracket | Word::OpenParen => {
+                    if rules_depth.is_none() && opens_macro_rules(&self.words, index) {
+                        rules_depth = Some(depth);
                     }
+                    depth += 1;
                 }
-                (Word::PrefixOperator(p1),
-                    Word::PrefixOperator(p2)) => {
-                    if p1 == "#" && p2 == "!" {
-                        result.push(Word::SlimInfix(p2));
-                    } else {
-                        result.push(self.words [index].clone());
+                Word::CloseBrace | Word::CloseBracket | Word::CloseAttribute | Word::CloseParen => {
+                    depth = depth.saturating_sub(1);
+                    if rules_depth == Some(depth) {
+                        rules_depth = None;
                     }
                 }
-
-                _ => result.push(self.words [index].clone()),
+                _ => {}
+            }
+            result.push(word.clone());
+            if rules_depth.is_some() && word == Word::CloseParen && is_repetition_end(&result) {
+                result.pop();
+                result.push(Word::CloseRepetition);
+                let next = self.words.get(index + 1).cloned().unwrap_or(Word::Eof);
+                let after = self.words.get(index + 2).cloned().unwrap_or(Word::Eof);
+                if is_repetition_operator(&next) {
+                    result.push(Word::Other(next.to_string()));
+                    index += 1;
+                } else if is_repetition_operator(&after) && is_separator(&next) {
+                    result.push(Word::Other(next.to_string() + &after.to_string()));
+                    index += 2;
+                }
             }
             index += 1;
         }
@@ -225,7 +348,8 @@ impl Typesetter {
                 result.push(Word::SemiColon);
             }
 
-            match decide_whitespace(context_stack.last().unwrap_or(&base_context), &word, &peek) {
+            let context = context_stack.last().unwrap_or(&base_context).clone();
+            match decide_whitespace(&self.config, &context, &word, &peek) {
                 Some(whitespace) => {
                     result.push(whitespace);
                 }
@@ -243,17 +367,28 @@ impl Typesetter {
             // decide context changes
             match peek {
                 Word::OpenBracket
-                | Word::OpenParen => context_stack.push(Context::List),
-                Word::OpenBrace => context_stack.push(Context::CodeBlock),
+                | Word::OpenParen
+                | Word::OpenBrace => {
+                    let opened = opened_context(&context, &self.words, index, &self.config);
+                    context_stack.push(opened);
+                }
+                // the parameters of a closure are set like a list
+                Word::OpenBar => context_stack.push(Context::List),
+                Word::CloseBar => {
+                    context_stack.pop();
+                }
                 Word::SlimInfix(s) => {
                     if s == "<" {
                         context_stack.push(Context::List)
-                    } else if s == ">" {
+                    }
+                    for _ in 0..closed_generics(&s) {
                         context_stack.pop();
                     }
                 }
                 Word::CloseBrace
                 | Word::CloseBracket
+                | Word::CloseAttribute
+                | Word::CloseRepetition
                 | Word::CloseParen => {
                     context_stack.pop();
                 }
@@ -265,12 +400,24 @@ impl Typesetter {
         self.words = Box::new(result);
     }
     //'
+    // Breaks lines that are longer than max_width at their last whitespace. The body of a closure
+    // is indented relative to the line the closure starts on, which may be such a broken line.
     fn handle_overlong_lines(&mut self) {
         let mut result = Vec::new();
         let mut index = 0usize;
         let mut column = 0i32;
         let mut intent = 0i32;
-        let mut index_at_last_ws = 0usize;
+        // where the current line can be broken, in the words and in the result: anywhere, and
+        // where it reads best (after a comma or before the body of a closure)
+        let mut last_ws:Option < (usize, usize) > = None;
+        let mut last_good_ws:Option < (usize, usize) > = None;
+        // indentation on top of intent, for closure bodies that start on a broken line
+        let mut extra = 0i32;
+        let mut outer_extras = Vec::new();
+        // how far the current line is indented beyond intent
+        let mut line_extra = 0i32;
+        let mut at_line_start = true;
+        let mut closure_on_line = false;
 
         loop {
             if index >= self.words.len() {
@@ -278,34 +425,92 @@ impl Typesetter {
             }
 
             let word = self.words[index].clone();
+            let len = word.clone().to_string().len() as i32;
 
-            intent += match word {
-                Word::LineBreakIntentPlus => SPACES_PER_TAP,
-                Word::LineBreakIntentMinus => - SPACES_PER_TAP,
-                _ => 0,
-            };
+            match word {
+                Word::LineBreakIntentPlus => {
+                    intent += self.config.tab_spaces;
+                    outer_extras.push(extra);
+                    if closure_on_line {
+                        extra = line_extra;
+                    }
+                }
+                Word::LineBreakIntentMinus => intent -= self.config.tab_spaces,
+                _ => {},
+            }
             match word {
                 Word::LineBreak
                 | Word::LineBreakDouble
                 | Word::LineBreakIntentPlus
-                | Word::LineBreakIntentMinus => column = intent,
-                _ => column += word.clone().to_string().len() as i32,
-            }
-            match word {
-                Word::Whitespace(_) => index_at_last_ws = index,
-                _ => {},
-            }
-            if column > MAX_LINE_LENGTH
-            && word.clone().to_string().len() < (MAX_LINE_LENGTH - intent) as usize {
-                for _ in 0..(index - index_at_last_ws) {
-                    result.pop();
+                | Word::LineBreakIntentMinus => {
+                    result.push(word.clone());
+                    if extra > 0 {
+                        result.push(Word::Whitespace(extra));
+                    }
+                    column = intent + extra;
+                    line_extra = extra;
+                    at_line_start = true;
+                    closure_on_line = false;
+                    last_ws = None;
+                    last_good_ws = None;
+                    // the closing line of a block still belongs to the line that opened it
+                    if word == Word::LineBreakIntentMinus {
+                        extra = outer_extras.pop().unwrap_or(0);
+                    }
+                }
+                Word::Whitespace(n) => {
+                    // |x| { stays together
+                    let before_body = result.last() == Some(&Word::CloseBar)
+                    && self.words.get(index + 1) == Some(&Word::OpenBrace);
+                    let good = match result.last() {
+                        Some(&Word::Comma) | Some(&Word::CloseBar) => true,
+                        _ => false,
+                    };
+                    if at_line_start {
+                        line_extra += n;
+                    } else if !before_body {
+                        last_ws = Some((index, result.len()));
+                        if good {
+                            last_good_ws = last_ws;
+                        }
+                    }
+                    column += len;
+                    result.push(word.clone());
+                }
+                _ => {
+                    at_line_start = false;
+                    if word == Word::CloseBar {
+                        closure_on_line = true;
+                    }
+                    column += len;
+                    // a verbatim word can span lines, only its last line counts
+                    if let Word::Verbatim(ref lines) = word {
+                        if lines.len() > 1 {
+                            column = intent + lines[lines.len() - 1].len() as i32;
+                            last_ws = None;
+                            last_good_ws = None;
+                        }
+                    }
+                    match last_good_ws.or(last_ws) {
+                        Some((ws_index, ws_len)) => {
+                            if column > self.config.max_width
+                            && len < self.config.max_width - intent {
+                                result.truncate(ws_len);
+                                index = ws_index;
+                                last_ws = None;
+                                last_good_ws = None;
+                                closure_on_line = false;
+                                line_extra = extra + self.config.tab_spaces;
+                                column = intent + line_extra;
+                                result.push(Word::LineBreak);
+                                result.push(Word::Whitespace(line_extra));
+                            } else {
+                                result.push(word.clone());
+                            }
+                        }
+                        None => result.push(word.clone()),
+                    }
                 }
-                index = index_at_last_ws;
-                column = intent + SPACES_PER_TAP;
-                result.push(Word::LineBreak);
-                result.push(Word::Whitespace(SPACES_PER_TAP));
-            } else {
-                result.push(word.clone());
             }
             index += 1;
         }
@@ -314,34 +519,614 @@ impl Typesetter {
         self.words = Box::new(result);
     }
 
-    pub fn to_string(&self) -> String {
-        let mut formated_source = "".to_string();
+    // The formated source and the byte offsets in it of the > and >> that sort_out_generics
+    // marked, verify only accepts closers that were split or joined there.
+    pub fn render(&self) -> (String, Vec < usize > ) {
+        let mut formated_source = self.preamble.clone();
+        let mut closers = Vec::new();
+        let newline = self.newline;
         let mut intent = 0i32;
 
         for word in self.words.iter() {
             intent += match *word {
-                Word::LineBreakIntentPlus => SPACES_PER_TAP,
-                Word::LineBreakIntentMinus => - SPACES_PER_TAP,
+                Word::LineBreakIntentPlus => self.config.tab_spaces,
+                Word::LineBreakIntentMinus => - self.config.tab_spaces,
                 _ => 0,
             };
-            limit(&mut intent, 0, MAX_INTENT);
+            limit(&mut intent, 0, self.config.max_indent);
             match *word {
                 Word::LineBreak | Word::LineBreakIntentPlus | Word::LineBreakIntentMinus => {
-                    formated_source.push_str("\n");
+                    formated_source.push_str(newline);
                     for _ in 0..intent {
                         formated_source.push_str(" ");
                     }
                 }
                 Word::LineBreakDouble => {
-                    formated_source.push_str("\n\n");
+                    formated_source.push_str(newline);
+                    formated_source.push_str(newline);
                     for _ in 0..intent {
                         formated_source.push_str(" ");
                     }
                 }
+                // block comments get the same line endings as the code around them, literals
+                // are left alone
+                Word::Comment(_) => {
+                    let comment = word.clone().to_string().replace("\r\n", "\n");
+                    formated_source.push_str(&comment.replace("\n", newline));
+                }
+                // a macro body keeps its lines, indented from the line it starts on, the lines
+                // of its content one level deeper if the closing } doesn't start a line
+                Word::Verbatim(ref lines) => {
+                    formated_source.push_str(&lines [0]);
+                    let closed = lines[lines.len() - 1].starts_with("}");
+                    let indent = if closed {
+                        intent
+                    } else {
+                        intent + self.config.tab_spaces
+                    };
+                    for line in lines[1..].iter() {
+                        formated_source.push_str(newline);
+                        if !line.is_empty() {
+                            for _ in 0..indent {
+                                formated_source.push_str(" ");
+                            }
+                            formated_source.push_str(line);
+                        }
+                    }
+                }
+                Word::SlimInfix(ref s) => {
+                    if s.starts_with(">") {
+                        closers.push(formated_source.len());
+                    }
+                    formated_source.push_str(s);
+                }
                 _ => formated_source.push_str(word.clone().to_string().as_ref()),
             }
         }
-        formated_source.trim_right_matches("\n").to_string()
+        // exactly one line ending at the end of the file
+        let mut formated_source = formated_source.trim_right().to_string();
+        if !formated_source.is_empty() {
+            formated_source.push_str(newline);
+        }
+        (formated_source, closers)
+    }
+}
+
+// Turns the body of a macro invocation like foo! {...} and a transcriber in braces in a
+// macro_rules! definition into a single verbatim word, unless the macro takes expressions or the
+// body looks like statements or items.
+fn set_aside_macro_bodies(source: &str, words: Vec < SpannedWord > , config: &Config) -> Vec < Word
+> {
+    let mut result = Vec::new();
+    let mut index = 0usize;
+    // the number of open delimiters, and that number inside the rules of a macro_rules!
+    let mut depth = 0usize;
+    let mut rules_depth = None;
+
+    loop {
+        if index >= words.len() {
+            break;
+        }
+        let transcriber = rules_depth == Some(depth) && is_transcriber(&words, index);
+        let body_end = if transcriber || is_macro_body(&words, index, config) {
+            find_close_brace(&words, index)
+        } else {
+            None
+        };
+        if let Some(end) = body_end {
+            if !looks_like_statements(&words [index + 1..end]) {
+                result.push(Word::Verbatim(verbatim_lines(source, &words [index..end + 1])));
+                index = end + 1;
+                continue;
+            }
+        }
+        match words[index].word {
+            Word::OpenBrace | Word::OpenBracket | Word::OpenParen => {
+                depth += 1;
+                if rules_depth.is_none() && is_macro_rules_body(&words, index) {
+                    rules_depth = Some(depth);
+                }
+            }
+            Word::CloseBrace | Word::CloseBracket | Word::CloseParen => {
+                if rules_depth == Some(depth) {
+                    rules_depth = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            _ => {}
+        }
+        result.push(words [index].word.clone());
+        index += 1;
+    }
+    result
+}
+
+// whether the { at index opens the rules of a macro_rules! definition, before macro_rules and the
+// ! are merged into one word
+fn is_macro_rules_body(words: & [SpannedWord], index: usize) -> bool {
+    if words[index].word != Word::OpenBrace || index < 3 {
+        return false;
+    }
+    words[index - 3].word == Word::Other("macro_rules".to_string())
+}
+
+// whether the word at index is the { of a transcriber, after the => of a rule
+fn is_transcriber(words: & [SpannedWord], index: usize) -> bool {
+    if words[index].word != Word::OpenBrace {
+        return false;
+    }
+    let before = words[..index].iter().rev().map( | spanned | &spanned.word).find( | w |
+        !is_layout(w));
+    before == Some(&Word::BinaryOperator("=>".to_string()))
+}
+
+// The source of words split into lines at the line breaks between them (a literal or comment keeps
+// its own), without trailing whitespace. The lines after the first lose the indentation they
+// have in common.
+fn verbatim_lines(source: &str, words: & [SpannedWord]) -> Vec < String > {
+    let tokens:Vec < &SpannedWord > = words.iter().filter( | spanned |
+        !is_line_break(&spanned.word)).collect();
+    let mut lines = Vec::new();
+    let mut line_start = tokens[0].start();
+    let mut i = 1usize;
+    loop {
+        if i >= tokens.len() {
+            break;
+        }
+        let gap_start = tokens[i - 1].end();
+        let gap = &source[gap_start..tokens [i].start()];
+        for offset in gap.match_indices("\n").map( | found | found.0) {
+            lines.push(source [line_start..gap_start + offset].trim_right().to_string());
+            line_start = gap_start + offset + 1;
+        }
+        i += 1;
+    }
+    lines.push(source [line_start..tokens [tokens.len() - 1].end()].to_string());
+
+    let common = lines[1..].iter().filter( | line | !line.trim().is_empty()).map( | line |
+        line.len() - line.trim_left().len()).min().unwrap_or(0);
+    let mut result = vec![lines [0].clone()];
+    for line in lines[1..].iter() {
+        if line.trim().is_empty() {
+            result.push(String::new());
+        } else {
+            result.push(line [common..].to_string());
+        }
+    }
+    result
+}
+
+fn is_line_break(word: &Word) -> bool {
+    match *word {
+        Word::LineBreak | Word::LineBreakDouble => true,
+        _ => false,
+    }
+}
+
+// whether the word at index is the { after the name and ! of a macro that doesn't take expressions
+fn is_macro_body(words: & [SpannedWord], index: usize, config: &Config) -> bool {
+    if words[index].word != Word::OpenBrace || index < 2 {
+        return false;
+    }
+    if words[index - 1].word != Word::PrefixOperator("!".to_string()) {
+        return false;
+    }
+    match words[index - 2].word {
+        Word::Other(ref name) => *name != "macro_rules" && !config.expression_macros.contains(name),
+        _ => false,
+    }
+}
+
+// the index of the } that closes the { at index
+fn find_close_brace(words: & [SpannedWord], index: usize) -> Option < usize > {
+    let mut depth = 0usize;
+    let mut i = index;
+    loop {
+        if i >= words.len() {
+            return None;
+        }
+        match words[i].word {
+            Word::OpenBrace => depth += 1,
+            Word::CloseBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+// Statements and items end with a ; or a }, and have no # but the one of an attribute. Two names
+// or literals only follow each other around a keyword (let x, x as u8 is an operator), and a =>
+// only comes inside of delimiters, in a match.
+fn looks_like_statements(body: & [SpannedWord]) -> bool {
+    let significant:Vec < &Word > = body.iter().map( | spanned | &spanned.word).filter( | w |
+        !is_layout(w)).collect();
+    let pound = Word::PrefixOperator("#".to_string());
+    let fat_arrow = Word::BinaryOperator("=>".to_string());
+    let mut depth = 0i32;
+    let mut i = 0usize;
+    loop {
+        if i >= significant.len() {
+            break;
+        }
+        match *significant[i] {
+            Word::OpenParen | Word::OpenBracket | Word::OpenBrace => depth += 1,
+            Word::CloseParen | Word::CloseBracket | Word::CloseBrace => depth -= 1,
+            _ => {}
+        }
+        if *significant[i] == pound {
+            match significant.get(i + 1) {
+                Some( && Word::OpenBracket) | Some( && Word::PrefixOperator(_)) => {}
+                _ => return false,
+            }
+        }
+        if *significant[i] == fat_arrow && depth == 0 {
+            return false;
+        }
+        if let (&Word::Other(ref a), Some( && Word::Other(ref b))) = (significant [i],
+            significant.get(i + 1)) {
+            if !KEYWORDS.contains( && a [..]) && !KEYWORDS.contains( && b [..]) {
+                return false;
+            }
+        }
+        i += 1;
+    }
+    match significant.last() {
+        None | Some( && Word::SemiColon) | Some( && Word::CloseBrace) => true,
+        _ => false,
+    }
+}
+
+// Splits off a byte order mark and a shebang line (but not an inner attribute like #![feature])
+// at the start of the source.
+fn split_preamble(source: &str) -> (&str, &str) {
+    let mut end = if source.starts_with("\u{feff}") {
+        "\u{feff}".len()
+    } else {
+        0
+    };
+    let rest = &source[end..];
+    if rest.starts_with("#!") && !rest[2..].trim_left().starts_with("[") {
+        end += match rest.find('\n') {
+            Some(i) => i + 1,
+            None => rest.len(),
+        };
+    }
+    (&source [..end], &source [end..])
+}
+
+// the number of generics a >, >>, >= or >>= closes (if it is a closing bracket at all)
+fn closed_generics(s: &str) -> usize {
+    match s {
+        ">" | ">=" => 1,
+        ">>" | ">>=" => 2,
+        _ => 0,
+    }
+}
+
+// whether the words before the < at index allow it to open generics
+fn is_generic_open(words: & [Word], index: usize) -> bool {
+    let before:Vec < &Word > = words[..index].iter().rev().filter( | w |
+        !is_layout(w)).take(2).collect();
+    let prev = match before.first() {
+        Some(prev) => *prev,
+        None => return true,
+    };
+    match *prev {
+        Word::Other(ref s) => {
+            let declares = match before.get(1) {
+                Some( && Word::Other(ref keyword)) => DECLARATIONS.contains( && keyword [..]),
+                _ => false,
+            };
+            declares || s == "impl" || s == "for" || is_type_name(s)
+        }
+        Word::SlimInfix(ref s) => s == "::",
+        // <T as Trait>::f() where an operand is expected
+        Word::BinaryOperator(_)
+        | Word::PrefixOperator(_)
+        | Word::OpenParen
+        | Word::OpenBracket
+        | Word::OpenBrace
+        | Word::CloseBrace
+        | Word::Comma
+        | Word::SemiColon
+        | Word::Colon => true,
+        _ => false,
+    }
+}
+
+static DECLARATIONS:[&'static str; 6] = ["fn", "struct", "enum", "trait", "type", "union"];
+
+// types start with an uppercase letter, constants are uppercase all the way through
+fn is_type_name(s: &str) -> bool {
+    let starts_upper = s.chars().next().map_or(false, | c | c.is_uppercase());
+    let is_constant = s.len() > 1 && s.chars().all( | c | !c.is_lowercase());
+    starts_upper && !is_constant
+}
+
+// Looks for the > that closes the < at index, before anything that can't be part of generics. A
+// >> may also close generics that are already open.
+fn has_generic_close(words: & [Word], index: usize, open: usize) -> bool {
+    let mut angles = 0isize;
+    let mut delimiters = 0isize;
+    let mut prev = &Word::Nope;
+    let mut i = index;
+    loop {
+        if i >= words.len() {
+            return false;
+        }
+        match words[i] {
+            Word::BinaryOperator(ref s) => {
+                if *s == "<" {
+                    angles += 1;
+                } else if closed_generics(s) > 0 {
+                    angles -= closed_generics(s) as isize;
+                    if angles <= 0 {
+                        return delimiters == 0 && - angles <= open as isize;
+                    }
+                } else if *s == "||" || (*s == "&&" && !expects_operand_after(prev, false)) {
+                    // a && b, but Vec<&&u8>
+                    return false;
+                }
+            }
+            Word::OpenParen | Word::OpenBracket => delimiters += 1,
+            Word::CloseParen | Word::CloseBracket => {
+                delimiters -= 1;
+                if delimiters < 0 {
+                    return false;
+                }
+            }
+            Word::SemiColon | Word::OpenBrace | Word::CloseBrace | Word::Eof => return false,
+            _ => {}
+        }
+        if !is_layout(&words [i]) {
+            prev = &words[i];
+        }
+        i += 1;
+    }
+}
+
+// whether a [ after these words opens an attribute
+fn opens_attribute(words: & [Word]) -> bool {
+    let pound = Word::PrefixOperator("#".to_string());
+    let bang = Word::PrefixOperator("!".to_string());
+    words.ends_with(& [pound.clone()]) || words.ends_with(& [pound, bang])
+}
+
+// whether the ) at the end of words closes a $(
+fn is_repetition_end(words: & [Word]) -> bool {
+    let mut depth = 0usize;
+    let mut i = words.len();
+    loop {
+        if i < 2 {
+            return false;
+        }
+        i -= 1;
+        match words[i] {
+            Word::CloseParen | Word::CloseRepetition => depth += 1,
+            Word::OpenParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return words[i - 1] == Word::PrefixOperator("$".to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_repetition_operator(word: &Word) -> bool {
+    match word.clone().to_string().as_ref() {
+        "*" | "+" | "?" => true,
+        _ => false,
+    }
+}
+
+// any single token but a delimiter can separate the repetitions
+fn is_separator(word: &Word) -> bool {
+    match *word {
+        Word::Other(_)
+        | Word::BinaryOperator(_)
+        | Word::PrefixOperator(_)
+        | Word::SlimInfix(_)
+        | Word::Comma
+        | Word::SemiColon
+        | Word::Colon => true,
+        _ => false,
+    }
+}
+
+// The context the delimiter after the word at index opens. In a macro definition the matchers
+// are set like lists, whatever their delimiters, and transcribers in braces like code blocks (the
+// ones that don't look like statements are verbatim words by now).
+fn opened_context(context: &Context, words: & [Word], index: usize, config: &Config) -> Context {
+    let delimiter = &words[index + 1];
+    // vec! {1, 2} is set like vec![1, 2]
+    let takes_expressions = match (index >= 1, &words [index]) {
+        (true, &Word::SlimInfix(ref s)) => {
+            match words[index - 1] {
+                Word::Other(ref name) => *s == "!" && config.expression_macros.contains(name),
+                _ => false,
+            }
+        }
+        _ => false,
+    };
+    if takes_expressions {
+        return Context::List;
+    }
+    if opens_macro_rules(words, index + 1) {
+        return Context::MacroRules;
+    }
+    let is_matcher = *context == Context::MacroRules
+    && words[index] != Word::BinaryOperator("=>".to_string());
+    // => {{...}}, the outer braces only delimit the block the macro expands to
+    if *delimiter == Word::OpenBrace && *context == Context::MacroRules && !is_matcher
+    && wraps_block(words, index + 1) {
+        return Context::List;
+    }
+    if *delimiter == Word::OpenBrace && !is_matcher {
+        Context::CodeBlock
+    } else {
+        Context::List
+    }
+}
+
+// whether the { at index opens the rules of a macro_rules! definition
+fn opens_macro_rules(words: & [Word], index: usize) -> bool {
+    if words[index] != Word::OpenBrace || index < 2 {
+        return false;
+    }
+    words[index - 2] == Word::Other("macro_rules!".to_string())
+}
+
+// whether the { at index holds nothing but another {...}
+fn wraps_block(words: & [Word], index: usize) -> bool {
+    if words.get(index + 1) != Some(&Word::OpenBrace) {
+        return false;
+    }
+    let mut depth = 0usize;
+    let mut i = index + 1;
+    loop {
+        if i >= words.len() {
+            return false;
+        }
+        match words[i] {
+            Word::OpenBrace => depth += 1,
+            Word::CloseBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    return words.get(i + 1) == Some(&Word::CloseBrace);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+// the $x of a macro fragment like $x:expr, its colon stays tight
+fn ends_with_fragment_name(words: & [Word]) -> bool {
+    let len = words.len();
+    if len < 2 || words[len - 2] != Word::PrefixOperator("$".to_string()) {
+        return false;
+    }
+    match words[len - 1] {
+        Word::Other(_) => true,
+        _ => false,
+    }
+}
+
+// the keywords that can stand next to a name, like fn f or Foo for Bar
+static KEYWORDS:[&'static str; 34] = ["async", "box", "break", "const", "continue", "crate",
+    "default", "dyn", "else", "enum", "extern", "fn", "for", "if", "impl", "in", "let", "loop",
+    "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct", "trait", "type",
+    "union", "unsafe", "use", "where", "while"];
+
+// keywords after which an operand follows, as in return -1, if !done, for &x in, &mut *p
+static OPERAND_KEYWORDS:[&'static str; 12] = ["return", "if", "match", "while", "in", "let", "mut",
+    "for", "box", "break", "move", "async"];
+
+// Looks for the | that ends the parameters of a closure opened by the | at index. Patterns and
+// types in the parameters can't hold a | of their own outside of delimiters. The bars of | | are
+// left alone, set tight they would become the single token ||.
+fn closure_params_end(words: & [Word], index: usize) -> Option < usize > {
+    let mut delimiters = 0isize;
+    let mut i = index + 1;
+    loop {
+        if i >= words.len() {
+            return None;
+        }
+        match words[i] {
+            Word::BinaryOperator(ref s) => {
+                if *s == "|" && delimiters == 0 {
+                    return if i > index + 1 {
+                        Some(i)
+                    } else {
+                        None
+                    };
+                } else if *s == "=>" {
+                    return None;
+                }
+            }
+            Word::OpenParen | Word::OpenBracket | Word::OpenBrace => delimiters += 1,
+            Word::CloseParen | Word::CloseBracket | Word::CloseAttribute | Word::CloseBrace => {
+                delimiters -= 1;
+                if delimiters < 0 {
+                    return None;
+                }
+            }
+            Word::SemiColon | Word::Eof => return None,
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+fn sort_out_operator(word: &Word, s: &str, prev: &Word, expects_operand: bool) -> Word {
+    match s {
+        "&" | "&&" | "*" | "-" => {
+            if expects_operand {
+                Word::PrefixOperator(s.to_string())
+            } else {
+                Word::BinaryOperator(s.to_string())
+            }
+        }
+        "!" => {
+            let inner_attribute = *prev == Word::PrefixOperator("#".to_string());
+            // the ! of a macro like println! comes where an operator would be expected
+            if inner_attribute || !expects_operand {
+                Word::SlimInfix(s.to_string())
+            } else {
+                Word::PrefixOperator(s.to_string())
+            }
+        }
+        _ => word.clone(),
+    }
+}
+
+fn expects_operand_after(word: &Word, expects_operand: bool) -> bool {
+    match *word {
+        Word::Other(ref s) => OPERAND_KEYWORDS.contains( && s [..]),
+        // x? is an operand, the others are unary operators
+        Word::PrefixOperator(ref s) => s != "?",
+        // Vec<&u8>, but Wrapping<u8> & mask
+        Word::SlimInfix(ref s) => s == ".." || s == "..." || s == "<",
+        Word::BinaryOperator(_)
+        | Word::OpenBar
+        | Word::CloseBar
+        | Word::Lifetime(_)
+        | Word::OpenParen
+        | Word::OpenBracket
+        | Word::OpenBrace
+        | Word::CloseBrace
+        | Word::Comma
+        | Word::SemiColon
+        | Word::Colon
+        // a macro body ends a statement, #[cfg(x)] -1 is an attribute on an operand
+        | Word::Verbatim(_)
+        | Word::CloseAttribute => true,
+        Word::CloseParen | Word::CloseBracket => false,
+        _ => expects_operand,
+    }
+}
+
+// & &x mustn't become &&x, which is lexed as one token
+fn space_between_prefixes(p: &str, q: &str) -> Option < Word > {
+    if p == "&" && q.starts_with("&") {
+        Some(Word::Whitespace(1))
+    } else {
+        None
+    }
+}
+
+fn is_layout(word: &Word) -> bool {
+    match *word {
+        Word::LineBreak | Word::LineBreakDouble | Word::Comment(_) => true,
+        _ => false,
     }
 }
 
@@ -353,10 +1138,60 @@ fn limit(var: &mut i32, low: i32, upper: i32) {
     }
 }
 
-fn decide_whitespace(context: &Context, word: &Word, peek: &Word) ->
+// impl<T> Foo, Vec<u8> = vec![], but foo::bar
+fn space_after_generics(s: &str) -> Option < Word > {
+    if closed_generics(s) > 0 {
+        Some(Word::Whitespace(1))
+    } else {
+        None
+    }
+}
+
+// The rules that are the same in every context, decide_whitespace checks them first. None if it is
+// up to the context.
+fn shared_whitespace(word: &Word, peek: &Word) -> Option < Option < Word >> {
+    match (word, peek) {
+        // no trailing whitespace before a line break kept from the source, the contexts
+        // decide what follows it
+        (_, &Word::LineBreak)
+        | (_, &Word::LineBreakDouble) => Some(None),
+        (&Word::LineBreak, _)
+        | (&Word::LineBreakDouble, _) => None,
+        // |x| x + 1, move || {}
+        (&Word::OpenBar, _)
+        | (_, &Word::CloseBar) => Some(None),
+        (&Word::CloseBar, _)
+        | (&Word::Other(_), &Word::OpenBar) => Some(Some(Word::Whitespace(1))),
+        (&Word::SlimInfix(_), &Word::Verbatim(_)) => Some(Some(Word::Whitespace(1))),
+        (&Word::PrefixOperator(ref p), &Word::PrefixOperator(ref q)) => {
+            Some(space_between_prefixes(p, q))
+        }
+        // foo(x?), bar()?
+        (_, &Word::PrefixOperator(ref p)) => {
+            if *p == "?" {
+                Some(None)
+            } else if let Word::Other(_) = *word {
+                // in &mut *p or (return -1)
+                Some(Some(Word::Whitespace(1)))
+            } else {
+                None
+            }
+        }
+        (&Word::SlimInfix(ref s), &Word::Other(_))
+        | (&Word::SlimInfix(ref s), &Word::Lifetime(_)) => Some(space_after_generics(s)),
+        // Vec<Vec<u8>>, Vec::<u8>
+        (&Word::SlimInfix(_), &Word::SlimInfix(_)) => Some(None),
+        _ => None,
+    }
+}
+
+fn decide_whitespace(config: &Config, context: &Context, word: &Word, peek: &Word) ->
 Option < Word > {
+    if let Some(whitespace) = shared_whitespace(word, peek) {
+        return whitespace;
+    }
     match *context {
-        Context::CodeBlock | Context::CurlyList => {
+        Context::CodeBlock | Context::CurlyList | Context::MacroRules => {
             match (word, peek) {
                 (&Word::SlimInfix(ref s), &Word::OpenBrace) => {
                     if *s == "::" {
@@ -365,8 +1200,8 @@ Option < Word > {
                         Some(Word::Whitespace(1))  // cases like <'a, 'b> {
                     }
                 }
-                (&Word::SlimInfix(_), &Word::BinaryOperator(_))
-                | (&Word::BinaryOperator(_), &Word::SemiColon) => None,
+                (&Word::SlimInfix(ref s), &Word::BinaryOperator(_)) => space_after_generics(s),
+                (&Word::BinaryOperator(_), &Word::SemiColon) => None,
                 (&Word::Other(ref s), &Word::OpenParen) => {
                     if *s == "if" || *s == "match" || *s == "for" || *s == "let" || *s == "while" {
                         Some(Word::Whitespace(1))
@@ -376,12 +1211,28 @@ Option < Word > {
                 }
                 (&Word::OpenBrace, &Word::CloseBrace) => None,
                 (&Word::OpenBrace, _) => Some(Word::LineBreakIntentPlus),
+                // <'a, 'b> stays on one line
+                (&Word::Comma, &Word::Lifetime(_)) => Some(Word::Whitespace(1)),
                 (_, &Word::CloseBrace) => Some(Word::LineBreakIntentMinus),
-                (_, &Word::LineBreakDouble)
-                | (&Word::LineBreakDouble, _)
-                | (_, &Word::LineBreak)
+                (&Word::LineBreakDouble, _)
                 | (&Word::LineBreak, _) => None,
+                // a comment after code stays on its line
+                (&Word::CloseAttribute, &Word::Comment(_))
+                | (&Word::CloseBrace, &Word::Comment(_)) => Some(Word::Whitespace(2)),
+                (&Word::CloseAttribute, _) => Some(Word::LineBreak),
+                // a macro body ends the statement, unless the expression goes on after it:
+                // foo! {...}.bar() or m! {...} + 1
+                (&Word::Verbatim(_), &Word::SemiColon)
+                | (&Word::Verbatim(_), &Word::Comma)
+                | (&Word::Verbatim(_), &Word::SlimInfix(_)) => None,
+                (&Word::Verbatim(_), &Word::BinaryOperator(_)) => Some(Word::Whitespace(1)),
+                (&Word::Verbatim(_), _) => Some(Word::LineBreak),
+                // name: Type in fields, parameters, bindings and bounds, and labels (a lifetime and
+                // a colon, whatever follows them: 'outer: loop, 'a: {)
+                (&Word::Colon, _) => Some(Word::Whitespace(1)),
                 (&Word::Other(_), &Word::Other(_))
+                | (&Word::Lifetime(_), &Word::Other(_))
+                | (&Word::Other(_), &Word::Lifetime(_))
                 | (&Word::BinaryOperator(_), _)
                 | (_, &Word::BinaryOperator(_))
                 | (_, &Word::OpenBrace) => Some(Word::Whitespace(1)),
@@ -396,14 +1247,14 @@ Option < Word > {
                 (&Word::CloseBrace, &Word::SemiColon)
                 | (&Word::CloseBrace, &Word::Comma) => None,
                 (&Word::SemiColon, _)
-                | (&Word::CloseBrace, &Word::Comment(_))
                 | (&Word::Comma, _)
                 | (&Word::Comment(_), _)
                 | (&Word::CloseBrace, _) => Some(Word::LineBreak),
                 (_, &Word::PrefixOperator(ref p)) => {
-                    match p.as_ref() {
-                        "#" => Some(Word::LineBreak),
-                        _ => Some(Word::Whitespace(1)),
+                    if *p == "#" {
+                        Some(Word::LineBreak)
+                    } else {
+                        Some(Word::Whitespace(1))
                     }
                 }
                 (_, &Word::Comment(_)) => Some(Word::Whitespace(2)),
@@ -413,8 +1264,25 @@ Option < Word > {
         Context::List => {
             match (word, peek) {
                 (&Word::LineBreak, _)
-                | (&Word::LineBreakDouble, _) => Some(Word::Whitespace(SPACES_PER_TAP)),
+                | (&Word::LineBreakDouble, _) => Some(Word::Whitespace(config.tab_spaces)),
+                // $(x;)*, $(x,)+ and $(#[$a])* in macro_rules!
+                (_, &Word::CloseRepetition) => None,
+                // => {{...}} in macro_rules!
+                (&Word::OpenBrace, &Word::OpenBrace) => None,
+                // fn f(#[cfg(a)] x: u8)
+                (&Word::PrefixOperator(_), &Word::OpenBracket) => None,
+                (&Word::CloseAttribute, _) => Some(Word::Whitespace(1)),
+                // fn $name() $body
+                (&Word::CloseParen, &Word::PrefixOperator(ref p)) => {
+                    if *p == "$" {
+                        Some(Word::Whitespace(1))
+                    } else {
+                        None
+                    }
+                }
                 (&Word::Other(_), &Word::Other(_))
+                | (&Word::Lifetime(_), &Word::Other(_))
+                | (&Word::Other(_), &Word::Lifetime(_))
                 | (&Word::BinaryOperator(_), _)
                 | (_, &Word::BinaryOperator(_))
                 | (&Word::Comma, _)
@@ -429,21 +1297,27 @@ Option < Word > {
         Context::ListExploded => {
             match (word, peek) {
                 (_, &Word::CloseBracket)
+                | (_, &Word::CloseAttribute)
+                | (_, &Word::CloseRepetition)
                 | (_, &Word::CloseParen) => Some(Word::LineBreakIntentMinus),
-                (_, &Word::LineBreakDouble)
-                | (&Word::LineBreakDouble, _)
-                | (_, &Word::LineBreak)
+                (&Word::LineBreakDouble, _)
                 | (&Word::LineBreak, _) => None,
+                (&Word::PrefixOperator(_), &Word::OpenBracket) => None,
+                (&Word::CloseAttribute, &Word::Comment(_)) => Some(Word::Whitespace(2)),
+                (&Word::CloseAttribute, _) => Some(Word::LineBreak),
                 (&Word::Other(_), &Word::Other(_))
+                | (&Word::Lifetime(_), &Word::Other(_))
+                | (&Word::Other(_), &Word::Lifetime(_))
+                | (&Word::Colon, _)
                 | (&Word::BinaryOperator(_), _)
                 | (_, &Word::BinaryOperator(_))
                 | (_, &Word::OpenBrace)
                 | (_, &Word::OpenBracket) => Some(Word::Whitespace(1)),
                 (&Word::SemiColon, &Word::Comment(_))
-                | (&Word::Comma, &Word::Comment(_)) => Some(Word::Whitespace(2)),
+                | (&Word::Comma, &Word::Comment(_))
+                | (&Word::CloseBracket, &Word::Comment(_)) => Some(Word::Whitespace(2)),
                 (&Word::CloseBracket, &Word::Comma) => None,
-                (&Word::CloseBracket, &Word::Comment(_))
-                | (&Word::Comma, _)
+                (&Word::Comma, _)
                 | (&Word::Comment(_), _)
                 | (&Word::CloseBracket, _)
                 | (&Word::CloseParen, _) => Some(Word::LineBreak),
@@ -453,3 +1327,10 @@ Option < Word > {
         }
     }
 }
+
+#[test]
+fn test_lex_error_after_shebang() {
+    let source = "#!/bin/x\nfn main() {\n    let s = \"abc;\n}\n";
+    let error = Typesetter::new(source, &Config::default()).err().unwrap();
+    assert_eq!((error.line, error.column), (3, 13));
+}
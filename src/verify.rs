@@ -0,0 +1,210 @@
+// Makes sure that formating only changed the layout. The formated source is lexed again and its
+// tokens are compared with the tokens of the original source, whitespace and comments aside. The
+// raw tokens of the lexer are compared (kind and text), not the words of the typesetter, which
+// leave out details that don't matter for the layout.
+use std::fmt;
+
+use token_handling::{
+    lex_raw,
+    line_and_column,
+    RawToken,
+    TokenKind,
+};
+
+// Tokens the typesetter inserts on purpose, as (previous token, inserted token, next token).
+static ALLOWED_INSERTIONS:[(&'static str, &'static str, &'static str); 1] = [("return", ";", "}")];
+
+// The > that close generics may be joined or split, the parser does the same: Vec<Vec<u8> > can
+// become Vec<Vec<u8>> and Option<u8>= None can become Option<u8> = None. That is only accepted
+// where the typesetter marked a closer, elsewhere a > = is not a >=.
+static ANGLE_CLOSERS:[&'static str; 5] = [">", ">>", ">=", ">>=", "="];
+
+// The first token where the formated source differs from the original, with its position in both.
+#[derive(Debug)]
+pub struct Divergence {
+    pub line:usize,
+    pub column:usize,
+    pub formated_line:usize,
+    pub formated_column:usize,
+    pub expected:String,
+    pub found:String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+            "{}:{}: expected {}, found {} (at {}:{} of the formated source)",
+            self.line,
+            self.column,
+            self.expected,
+            self.found,
+            self.formated_line,
+            self.formated_column)
+    }
+}
+
+// closers are the byte offsets in formated of the > and >> that close generics
+pub fn verify(source: &str, formated: &str, closers: & [usize]) -> Result < (),
+Divergence > {
+    let expected = significant_tokens(lex_raw(source).unwrap_or(Vec::new()));
+    let found = match lex_raw(formated) {
+        Ok(tokens) => significant_tokens(tokens),
+        Err(e) => {
+            return Err(Divergence {
+                line:0,
+                column:0,
+                formated_line:e.line,
+                formated_column:e.column,
+                expected:"valid tokens".to_string(),
+                found:format!("a lexer error ({})", e.message),
+            })
+        }
+    };
+
+    let mut i = 0usize;
+    let mut j = 0usize;
+    loop {
+        if i >= expected.len() && j >= found.len() {
+            return Ok(());
+        }
+        if i < expected.len() && j < found.len() && same_token(&expected [i], &found [j]) {
+            i += 1;
+            j += 1;
+        } else if j < found.len() && is_allowed_insertion(&found, j) {
+            j += 1;
+        } else if let Some((split, joined)) = same_closers(&expected [i..], &found [j..], closers) {
+            i += split;
+            j += joined;
+        } else {
+            let (line, column) = position(source, &expected, i);
+            let (formated_line, formated_column) = position(formated, &found, j);
+            return Err(Divergence {
+                line:line,
+                column:column,
+                formated_line:formated_line,
+                formated_column:formated_column,
+                expected:describe(expected.get(i)),
+                found:describe(found.get(j)),
+            });
+        }
+    }
+}
+
+// everything that can change without changing the meaning is left out
+fn significant_tokens(tokens: Vec < RawToken > ) -> Vec < RawToken > {
+    tokens.into_iter().filter(is_significant).collect()
+}
+
+// doc comments are attributes, they count
+fn is_significant(raw: &RawToken) -> bool {
+    raw.kind != TokenKind::Whitespace && raw.kind != TokenKind::Comment
+}
+
+fn same_token(a: &RawToken, b: &RawToken) -> bool {
+    if a.kind != b.kind {
+        return false;
+    }
+    match a.kind {
+        // the line endings in (and of) these follow newline_style
+        TokenKind::Shebang | TokenKind::DocComment => {
+            unix_newlines(&a.text) == unix_newlines(&b.text)
+        }
+        _ => a.text == b.text,
+    }
+}
+
+fn unix_newlines(text: &str) -> String {
+    text.replace("\r\n", "\n").trim_right_matches('\r').to_string()
+}
+
+fn is_allowed_insertion(tokens: & [RawToken], index: usize) -> bool {
+    if index == 0 || index + 1 >= tokens.len() {
+        return false;
+    }
+    let inserted = (&tokens [index - 1].text [..], &tokens [index].text [..],
+        &tokens [index + 1].text [..]);
+    ALLOWED_INSERTIONS.iter().any( | allowed | *allowed == inserted)
+}
+
+// The number of tokens on both sides that spell the same closing angle brackets, like >>= and >> =.
+// The > and >> on the formated side have to be at one of the closers.
+fn same_closers(expected: & [RawToken],
+    found: & [RawToken],
+    closers: & [usize]) -> Option < (usize, usize) > {
+    let mut expected_text = String::new();
+    let mut found_text = String::new();
+    let mut i = 0usize;
+    let mut j = 0usize;
+    loop {
+        // the shorter side takes its next token
+        let take_expected = expected_text.len() <= found_text.len();
+        let next = if take_expected {
+            expected.get(i)
+        } else {
+            found.get(j)
+        };
+        let text = match next {
+            Some(raw) => &raw.text[..],
+            None => return None,
+        };
+        if !ANGLE_CLOSERS.contains(&text) {
+            return None;
+        }
+        if take_expected {
+            expected_text.push_str(text);
+            i += 1;
+        } else {
+            if text.starts_with(">") && !closers.contains(&found [j].start) {
+                return None;
+            }
+            found_text.push_str(text);
+            j += 1;
+        }
+        if !expected_text.starts_with(">") {
+            return None;
+        }
+        if expected_text == found_text {
+            return Some((i, j));
+        }
+        let found_is_prefix = expected_text.starts_with(&found_text [..]);
+        let expected_is_prefix = found_text.starts_with(&expected_text [..]);
+        if !found_is_prefix && !expected_is_prefix {
+            return None;
+        }
+    }
+}
+
+// the position of the index-th token, or of the end of the source
+fn position(source: &str, tokens: & [RawToken], index: usize) -> (usize, usize) {
+    let pos = tokens.get(index).map_or(source.len(), | raw | raw.start);
+    line_and_column(source, pos)
+}
+
+fn describe(token: Option < &RawToken > ) -> String {
+    match token {
+        Some(raw) => format!("`{}`", raw.text),
+        None => "the end of the file".to_string(),
+    }
+}
+
+#[test]
+fn test_verify() {
+    assert!(verify("fn main() {return}", "fn main() {\n    return;\n}", & []).is_ok());
+    assert!(verify("let x = & &y; // a", "let x = & &y;\n", & []).is_ok());
+
+    let divergence = verify("let x = & &y;", "let x =\n&&y;", & []).unwrap_err();
+    assert_eq!((divergence.line, divergence.column), (1, 9));
+    assert_eq!((divergence.formated_line, divergence.formated_column), (2, 1));
+    assert_eq!(divergence.expected, "`&`");
+    assert_eq!(divergence.found, "`&&`");
+    assert!(verify("foo(a)", "foo(a);", & []).is_err());
+    let formated = "let x: Vec<Vec<u8>> = y;";
+    assert!(verify("let x: Vec<Vec<u8> >= y;", formated, & [formated.find(">>").unwrap()]).is_ok());
+    assert!(verify("let x: Vec<Vec<u8> >= y;", formated, & []).is_err());
+    assert!(verify("a > b", "a >> b", & []).is_err());
+    // a comparison is not a closer, even if it is spelled like one
+    assert!(verify("foo(A < B, C >= D)", "foo(A<B, C> = D)", & []).is_err());
+    // comments may change, doc comments may not
+    assert!(verify("// a\nfn f() {}", "// b\nfn f() {}", & []).is_ok());
+    assert!(verify("/// a\nfn f() {}", "/// b\nfn f() {}", & []).is_err());
+}
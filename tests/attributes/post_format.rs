@@ -0,0 +1,46 @@
+#![allow(dead_code)]
+#![cfg_attr(feature = "nightly", feature(test))]
+#[derive(Debug, Clone)]
+struct S {
+    #[allow(unused)]
+    x: u8,
+    #[cfg(test)]
+    y: u8,
+}
+enum E {
+    #[doc = "a"]
+    A,
+    B,
+}
+#[test]
+fn t(#[allow(unused)] a: u8) {
+    #[allow(unused)]
+    let x = 1;
+    match x {
+        #[cfg(a)]
+        1 => 2,
+        _ => 3,
+    }
+    let v = vec![#[cfg(a)] 1, 2];
+    let w = vec![1, #[cfg(a)] -1];
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq,
+    Hash))]
+pub struct P;
+fn u(
+    #[cfg(a)]
+    a: u8,
+    b: u8,
+) {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, RustcEncodable,
+    RustcDecodable)]
+struct Q {
+    #[allow(unused)]
+    x: u8
+}  // c
+#[inline]  // c
+fn w(
+    #[cfg(a)]  // d
+    a: u8,
+) {}
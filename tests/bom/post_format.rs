@@ -0,0 +1,4 @@
+﻿use std::io;
+fn main() {
+    let x = io::stdin();
+}
@@ -0,0 +1,2 @@
+﻿use std::io;
+fn main(){let x=io::stdin();}
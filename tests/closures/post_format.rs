@@ -0,0 +1,33 @@
+fn main() {
+    let f = |x| x + 1;
+    let g = move || {};
+    let h = |a: u8, b: &str| -> u8 {
+        a
+    };
+    let v: Vec<u8> = w.iter().map(|&s| s * 2).filter(|x| *x > 1).collect();
+    thread::spawn(move || {
+        work();
+        more();
+    });
+    let k = async move |(a, b)| a | b;
+    let e = a || b;
+    let m = x | y;
+    match x {
+        A | B => 1,
+    }
+    foo(1, |x| {
+        x
+    });
+    let n = | | 1;
+    let x = foo(
+        a,
+        |y| {
+            y
+        },
+        b
+    );
+    let total = items.iter().filter(|item|
+        item.is_visible() && item.weight > threshold).map(|item| {
+            item.weight * 2
+        }).sum();
+}
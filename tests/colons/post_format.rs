@@ -0,0 +1,34 @@
+struct Typesetter {
+    words: Box<Vec<Word>>,
+    config: Config,
+}
+enum E {
+    A {
+        x: u8
+    },
+    B(u8)
+}
+fn foo<T: Clone>(a: u8, b: &str, c: [u8; 4]) -> u8 where T: Into<String> {
+    let mut args: Vec<String> = Vec::new();
+    let p = Point {
+        x: 1,
+        y: 2
+    };
+    let q = Point {
+        x: 1,
+        y: 2,
+    };
+    'outer: loop {
+        break 'outer;
+    }
+    std::mem::drop(x);
+    let t: (u8, u8) = (1, 2);
+}
+macro_rules! m {
+    ($x:expr, $t:ty) => ($x as $t);
+}
+fn bar(
+    a: u8,
+    b: u16,
+) {}
+impl<'a, T: 'a> X for Y<'a, T> {}
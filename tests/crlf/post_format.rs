@@ -0,0 +1,9 @@
+fn main() {
+    // hi
+    let s = "a
+b";
+
+    /* x
+    y */
+    foo();
+}
@@ -0,0 +1,25 @@
+fn foo<'a, T>(x: &'a T) -> Option<T> where T: Into<String> {
+    let v: Vec<Vec<u8>> = Vec::new();
+    let m: HashMap<String, Vec<u8>> = HashMap::new();
+    let w = Vec::<u8>::new();
+    let y: Vec<u8> = v;
+    if a < b && c > d {
+        x << 2;
+    }
+    if a<b {
+        e >>= 1;
+    }
+    <T as Trait>::f();
+    let z: Option<Box<Fn(u8)>>= None;
+    let q: Vec<Vec<u8> > = x;
+}
+impl<T: Clone> Foo<T> for Bar {
+}
+struct S<'a> { x: &'a u8 }
+fn paths() {
+    let m: std::collections::HashMap<u8,Vec<u8>>= std::collections::HashMap::new();
+    let n = v.iter().collect::<Vec<_>>();
+    let s = mem::size_of::<T>();
+    let t: Vec<Vec<Vec<u8> > >;
+    let u = x >= y && a >> b > c;
+}
@@ -11,4 +11,4 @@ fn main() {
     // Error!
     _immutable_binding += 1;
     // FIXME ^ Comment out this line
-}
\ No newline at end of file
+}
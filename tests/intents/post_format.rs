@@ -1,4 +1,4 @@
 fn main() {
     // Print text to the console
     println!("Hello World!");
-}
\ No newline at end of file
+}
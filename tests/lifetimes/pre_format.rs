@@ -0,0 +1,35 @@
+struct Parser {
+    input:&'static str,
+    rest: &'static   mut u8,
+}
+
+fn find(haystack: &'static str) -> bool {
+    'outer:loop {
+        for c in haystack.chars() {
+            if c == 'x' {
+                break   'outer;
+            }
+            continue 'outer;
+        }
+    }
+    'search:   while true {
+        break 'search;
+    }
+    false
+}
+
+struct Pair<'a,'b:'a> {
+    x:Vec<&'a  u8>,
+    y:Option<&'b mut  Pair<'a,'b>>,
+}
+
+impl<'a,  'b> Pair<'a,'b> {
+    fn first(&self) -> Option<&'a   u8> {
+        'block:{
+            'rows:for row in self.x.iter() {
+                break 'rows;
+            }
+        }
+        None
+    }
+}
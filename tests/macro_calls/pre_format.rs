@@ -0,0 +1,29 @@
+fn main() {
+    println!("{} {}", a, b&c);
+    let v = vec![1,2,3];
+    let w = vec!{1, 2};
+    assert_eq!(a, -b);
+    let m = matches!(x, Some(_));
+    let page = html! {
+        <div class="x">  { name } </div>
+    };
+    thread_local! {
+        static COUNTER: Cell<u32> = Cell::new(0);
+    }
+    lazy_static!{ static ref X:u8 = 1; }
+    let q = quote! { fn #name() {} };
+    select! { a = rx.recv() => 1, b = ry.recv() => 2, }
+    if x {
+    let list = html! {
+          <ul>
+              <li>{ "a" }</li>   
+          </ul>
+      };
+    }
+    css! { margin: 0 auto; }
+    words! { the end; }
+    foo! { a b }
+    *p = 1;
+    foo! { a b }.bar();
+    let x = m! { a b } + 1;
+}
@@ -0,0 +1,36 @@
+macro_rules! square {
+    ($x:expr) => ($x * $x);
+}
+macro_rules! hash {
+    ($($key:expr => $value:expr),*) => {{
+        let mut map = HashMap::new();
+        $(map.insert($key, $value);)*
+        map
+    }};
+    ($($key:expr => $value:expr,)+) => { hash!($($key => $value),+) };
+}
+macro_rules! try_opt {
+    ($e:expr) => (match $e {
+        Some(x) => x,
+        None => return None
+    });
+}
+macro_rules! items {
+    ($($(#[$attr:meta])* fn $name:ident() $body:block)*) => {
+        $($(#[$attr])* fn $name() $body)*
+    }
+}
+macro_rules! sum {
+    () => (0);
+    ($head:expr $(, $tail:expr)*) => ($head + sum!($($tail),*));
+}
+fn main() {
+    let x = square!(2);
+}
+macro_rules! braces {
+    {$x:expr; $($rest:tt)*} => [$x, braces!($($rest)*)];
+    ($a:ident) => {
+        let $a = 1;
+    };
+    ($($t:tt)*) => {   names $($t)*   as they come };
+}
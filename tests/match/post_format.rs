@@ -4,4 +4,4 @@ match format_file(filename.as_ref()) {
         return;
     }
     Ok(_) => {}
-}
\ No newline at end of file
+}
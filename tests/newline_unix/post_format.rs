@@ -0,0 +1,13 @@
+fn main() {
+    // crlf
+    let s = "a
+b";
+
+    /** a
+ b */
+    foo();
+    let page = html! {
+        <p>"x
+y"</p>
+    };
+}
@@ -0,0 +1,11 @@
+fn main() {
+    // lf
+    let s = "a
+b";
+
+    foo();
+    let page = html! {
+        <p>"x
+y"</p>
+    };
+}
@@ -0,0 +1,34 @@
+fn f(x: &str, p: *const u8, r: &&u8) -> &str {
+    let a = b & c;
+    let d = -1;
+    x = -y;
+    let e = a - b;
+    let f = a * b;
+    *p = 2;
+    let g = &*p;
+    let h = & &x;
+    let i = &&x;
+    if !done && a && b {
+        return !x;
+    }
+    for &x in &v {
+        println!("{}", -x);
+    }
+    match x {
+        &Some(ref y) => -1,
+        -2 => *y,
+    }
+    let v: Vec<&str> = w.iter().map(|&s| s * -1).collect();
+    let k = foo(-1, &mut *p) - bar()? * 3;
+    let r = x[..-1];
+    unsafe {
+        *p
+    }
+}
+#![allow(x)]
+fn after_generics() {
+    let a = b as Wrapping<u8> & c;
+    let d = e as Wrapping<u8> - 1;
+    let g = h as Wrapping<u8> * 2;
+    let v: Vec<&&u8> = w;
+}
@@ -0,0 +1,5 @@
+#!/usr/bin/env run-cargo-script
+// cargo-deps: time="0.1"
+fn main() {
+    println!("hello");
+}